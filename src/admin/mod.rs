@@ -19,7 +19,7 @@ use crate::{
         etcd::{json_to_resource, EtcdClientWrapper},
         Admin, Pingsix,
     },
-    proxy::plugin::build_plugin,
+    proxy::{consumer::check_unique_credentials, plugin::build_plugin},
 };
 
 #[derive(Debug)]
@@ -109,6 +109,11 @@ impl AdminHttpApp {
         };
 
         this.route(
+            "/apisix/admin/{resource}",
+            Method::GET,
+            Box::new(ResourceListHandler {}),
+        )
+        .route(
             "/apisix/admin/{resource}/{id}",
             Method::PUT,
             Box::new(ResourcePutHandler {}),
@@ -202,15 +207,15 @@ impl Handler for ResourcePutHandler {
         let resource_type = params
             .get("resource")
             .ok_or_else(|| ApiError::MissingParameter("resource".into()))?;
-        let key = format!(
-            "{}/{}",
-            resource_type,
-            params
-                .get("id")
-                .ok_or_else(|| ApiError::MissingParameter("id".into()))?
-        );
+        let id = params
+            .get("id")
+            .ok_or_else(|| ApiError::MissingParameter("id".into()))?;
+        let key = format!("{}/{}", resource_type, id);
 
         validate_resource(resource_type, &body_data)?;
+        if resource_type == "consumers" {
+            validate_consumer_credentials(etcd, id, &body_data).await?;
+        }
         etcd.put(&key, body_data)
             .await
             .map_err(|e| ApiError::EtcdError(e.to_string()))?;
@@ -259,6 +264,120 @@ impl Handler for ResourceGetHandler {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct ListWrapper {
+    total: usize,
+    list: Vec<ListItem>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ListItem {
+    key: String,
+    value: serde_json::Value,
+}
+
+/// Filters supported by the list endpoint, e.g. `?name=user&label=team:payments`.
+#[derive(Default)]
+struct ListFilter {
+    name: Option<String>,
+    // label key and optional expected value
+    labels: Vec<(String, Option<String>)>,
+}
+
+impl ListFilter {
+    /// Parses the percent-encoded `name` and `label` query arguments.
+    fn from_query(query: &str) -> Self {
+        let mut filter = Self::default();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match key.as_ref() {
+                "name" if !value.is_empty() => filter.name = Some(value.into_owned()),
+                "label" if !value.is_empty() => {
+                    let label = match value.split_once(':') {
+                        Some((k, v)) => (k.to_string(), Some(v.to_string())),
+                        None => (value.to_string(), None),
+                    };
+                    filter.labels.push(label);
+                }
+                _ => {}
+            }
+        }
+        filter
+    }
+
+    fn matches(&self, resource: &serde_json::Value) -> bool {
+        if let Some(name) = &self.name {
+            let matched = resource
+                .get("name")
+                .and_then(|v| v.as_str())
+                .is_some_and(|v| v.contains(name.as_str()));
+            if !matched {
+                return false;
+            }
+        }
+
+        self.labels.iter().all(|(key, expected)| {
+            let actual = resource
+                .get("labels")
+                .and_then(|labels| labels.get(key))
+                .and_then(|v| v.as_str());
+            match expected {
+                Some(expected) => actual == Some(expected.as_str()),
+                None => actual.is_some(),
+            }
+        })
+    }
+}
+
+struct ResourceListHandler;
+
+#[async_trait]
+impl Handler for ResourceListHandler {
+    async fn handle(
+        &self,
+        etcd: &EtcdClientWrapper,
+        http_session: &mut ServerSession,
+        params: BTreeMap<String, String>,
+    ) -> ApiResult<Response<Vec<u8>>> {
+        let resource_type = params
+            .get("resource")
+            .ok_or_else(|| ApiError::MissingParameter("resource".into()))?;
+        if !matches!(
+            resource_type.as_str(),
+            "routes" | "upstreams" | "services" | "global_rules" | "consumers"
+        ) {
+            return Err(ApiError::InvalidRequest("Unsupported resource type".into()));
+        }
+
+        let filter =
+            ListFilter::from_query(http_session.req_header().uri.query().unwrap_or_default());
+
+        let kvs = etcd
+            .list(resource_type)
+            .await
+            .map_err(|e| ApiError::EtcdError(e.to_string()))?;
+
+        let list: Vec<ListItem> = kvs
+            .into_iter()
+            .filter_map(|(key, value)| match serde_json::from_slice(&value) {
+                Ok(value) => Some(ListItem { key, value }),
+                Err(e) => {
+                    log::error!("Invalid JSON data for {}: {}", key, e);
+                    None
+                }
+            })
+            .filter(|item| filter.matches(&item.value))
+            .collect();
+
+        let wrapper = ListWrapper {
+            total: list.len(),
+            list,
+        };
+        let json_vec =
+            serde_json::to_vec(&wrapper).map_err(|e| ApiError::InternalError(e.to_string()))?;
+        Ok(ResponseHelper::success(json_vec, Some("application/json")))
+    }
+}
+
 struct ResourceDeleteHandler;
 
 #[async_trait]
@@ -340,10 +459,47 @@ fn validate_resource(resource_type: &str, body_data: &[u8]) -> ApiResult<()> {
             rule.validate()
                 .map_err(|e| ApiError::ValidationError(e.to_string()))
         }
+        "consumers" => {
+            let consumer = validate_with_plugins::<config::Consumer>(body_data)?;
+            consumer
+                .validate()
+                .map_err(|e| ApiError::ValidationError(e.to_string()))
+        }
         _ => Err(ApiError::InvalidRequest("Unsupported resource type".into())),
     }
 }
 
+/// Rejects a consumer sharing an identifying credential with another consumer.
+async fn validate_consumer_credentials(
+    etcd: &EtcdClientWrapper,
+    id: &str,
+    body_data: &[u8],
+) -> ApiResult<()> {
+    let mut consumer = json_to_resource::<config::Consumer>(body_data)
+        .map_err(|e| ApiError::InvalidRequest(format!("Invalid JSON data: {}", e)))?;
+    consumer.username = id.to_string();
+
+    let kvs = etcd
+        .list("consumers")
+        .await
+        .map_err(|e| ApiError::EtcdError(e.to_string()))?;
+    let others: Vec<config::Consumer> = kvs
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let username = key.rsplit('/').next()?;
+            if username == id {
+                return None;
+            }
+            let mut other = json_to_resource::<config::Consumer>(&value).ok()?;
+            other.username = username.to_string();
+            Some(other)
+        })
+        .collect();
+
+    check_unique_credentials(others.iter().chain([&consumer]))
+        .map_err(|e| ApiError::ValidationError(e.to_string()))
+}
+
 fn validate_with_plugins<T: PluginValidatable + DeserializeOwned>(
     body_data: &[u8],
 ) -> ApiResult<T> {
@@ -385,3 +541,44 @@ impl PluginValidatable for config::GlobalRule {
         Ok(())
     }
 }
+
+impl PluginValidatable for config::Consumer {
+    fn validate_plugins(&self) -> Result<(), Box<dyn Error>> {
+        for (name, value) in &self.plugins {
+            build_plugin(name, value.clone())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_list_filter() {
+        let resource = json!({
+            "name": "user-service-route",
+            "labels": {"team": "payments", "env": "prod"},
+        });
+
+        assert!(ListFilter::from_query("").matches(&resource));
+        assert!(ListFilter::from_query("name=user").matches(&resource));
+        assert!(!ListFilter::from_query("name=order").matches(&resource));
+        assert!(ListFilter::from_query("label=team").matches(&resource));
+        assert!(ListFilter::from_query("label=team:payments&label=env:prod").matches(&resource));
+        assert!(!ListFilter::from_query("label=team:search").matches(&resource));
+        assert!(!ListFilter::from_query("name=user&label=owner").matches(&resource));
+        assert!(!ListFilter::from_query("name=user").matches(&json!({"uri": "/"})));
+
+        // Values are percent-decoded
+        let resource = json!({"name": "user service", "labels": {"team": "a&b"}});
+        assert!(ListFilter::from_query("name=user%20service").matches(&resource));
+        assert!(ListFilter::from_query("name=user+service").matches(&resource));
+        assert!(ListFilter::from_query("label=team:a%26b").matches(&resource));
+        assert!(ListFilter::from_query("label=team%3Aa%26b").matches(&resource));
+        assert!(!ListFilter::from_query("label=team:a&b").matches(&resource));
+    }
+}
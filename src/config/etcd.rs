@@ -270,6 +270,33 @@ impl EtcdClientWrapper {
             .map(|resp| resp.kvs().first().map(|kv| kv.value().to_vec()))
     }
 
+    /// Lists all key-value pairs stored under the given key, e.g. `routes`.
+    pub async fn list(&self, key: &str) -> Result<Vec<(String, Vec<u8>)>, EtcdError> {
+        let client_arc = self.ensure_connected().await?;
+        let mut client_guard = client_arc.lock().await;
+
+        let client = client_guard
+            .as_mut()
+            .ok_or(EtcdError::ClientNotInitialized)?;
+
+        let options = GetOptions::new().with_prefix();
+        client
+            .get(format!("{}/", self.with_prefix(key)), Some(options))
+            .await
+            .map_err(|e| EtcdError::ListOperationFailed(e.to_string()))
+            .map(|resp| {
+                resp.kvs()
+                    .iter()
+                    .map(|kv| {
+                        (
+                            String::from_utf8_lossy(kv.key()).into_owned(),
+                            kv.value().to_vec(),
+                        )
+                    })
+                    .collect()
+            })
+    }
+
     pub async fn put(&self, key: &str, value: Vec<u8>) -> Result<(), EtcdError> {
         let client_arc = self.ensure_connected().await?;
         let mut client_guard = client_arc.lock().await;
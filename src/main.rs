@@ -16,8 +16,14 @@ use sentry::IntoDsn;
 use admin::AdminHttpApp;
 use config::{etcd::EtcdConfigSync, Config, Tls};
 use proxy::{
-    event::ProxyEventHandler, global_rule::load_static_global_rules, route::load_static_routes,
-    service::load_static_services, upstream::load_static_upstreams,
+    consumer::load_static_consumers,
+    discovery::{set_discovery_config, set_dns_config},
+    event::ProxyEventHandler,
+    global_rule::load_static_global_rules,
+    plugin::prometheus::set_route_labels,
+    route::{load_static_routes, set_route_match_mode},
+    service::load_static_services,
+    upstream::load_static_upstreams,
 };
 use service::http::HttpService;
 
@@ -27,6 +33,9 @@ fn main() {
     // 加载配置和命令行参数
     let opt = Opt::parse_args();
     let config = Config::load_yaml_with_opt_override(&opt).expect("Failed to load configuration");
+    set_route_match_mode(config.pingsix.route_match_mode);
+    set_dns_config(config.pingsix.dns.clone());
+    set_discovery_config(config.pingsix.discovery.clone());
 
     // 配置同步
     let etcd_config = if let Some(etcd_cfg) = &config.pingsix.etcd {
@@ -41,6 +50,7 @@ fn main() {
         load_static_upstreams(&config).expect("Failed to load static upstreams");
         load_static_services(&config).expect("Failed to load static services");
         load_static_global_rules(&config).expect("Failed to load static global rules");
+        load_static_consumers(&config).expect("Failed to load static consumers");
         load_static_routes(&config).expect("Failed to load  static routes");
         None
     };
@@ -126,6 +136,7 @@ fn add_optional_services(server: &mut Server, cfg: &config::Pingsix) {
 
     if let Some(prometheus_cfg) = &cfg.prometheus {
         log::info!("Adding Prometheus Service...");
+        set_route_labels(prometheus_cfg.route_labels.clone());
         let mut prometheus_service_http = Service::prometheus_http_service();
         prometheus_service_http.add_tcp(&prometheus_cfg.address.to_string());
         server.add_service(prometheus_service_http);
@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use pingora_error::{Error, ErrorType::ReadError, Result};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+
+use super::{
+    plugin::{
+        basic_auth, build_plugin, constant_time_eq, hmac_auth, jwt_auth, key_auth, ProxyPlugin,
+    },
+    Identifiable, MapOperations,
+};
+
+/// Plugins authenticating consumers and the credential field identifying a consumer,
+/// their consumer configuration holds credentials and is not executed.
+const AUTH_PLUGINS: [(&str, &str); 4] = [
+    (key_auth::PLUGIN_NAME, "key"),
+    (jwt_auth::PLUGIN_NAME, "key"),
+    (basic_auth::PLUGIN_NAME, "username"),
+    (hmac_auth::PLUGIN_NAME, "access_key"),
+];
+
+/// Represents a consumer and the plugins it brings to the routes it calls.
+pub struct ProxyConsumer {
+    pub inner: config::Consumer,
+    pub plugins: Vec<Arc<dyn ProxyPlugin>>,
+    /// Key verifying the tokens of the consumer, built once from its jwt auth config
+    pub jwt_key: Option<jwt_auth::ConsumerKey>,
+}
+
+impl Identifiable for ProxyConsumer {
+    fn id(&self) -> String {
+        self.inner.username.clone()
+    }
+
+    fn set_id(&mut self, id: String) {
+        self.inner.username = id;
+    }
+}
+
+impl From<config::Consumer> for ProxyConsumer {
+    fn from(value: config::Consumer) -> Self {
+        Self {
+            inner: value,
+            plugins: Vec::new(),
+            jwt_key: None,
+        }
+    }
+}
+
+impl ProxyConsumer {
+    pub fn new_with_plugins(consumer: config::Consumer) -> Result<Self> {
+        let mut proxy_consumer = Self::from(consumer.clone());
+
+        for (name, value) in consumer.plugins {
+            log::info!("Loading plugin: {}", name);
+            if name == jwt_auth::PLUGIN_NAME {
+                proxy_consumer.jwt_key = jwt_auth::create_consumer_key(value.clone())?;
+            }
+
+            // Credentials are validated by building the auth plugin
+            let plugin = build_plugin(&name, value)?;
+            if !AUTH_PLUGINS.iter().any(|(plugin, _)| *plugin == name) {
+                proxy_consumer.plugins.push(plugin);
+            }
+        }
+
+        Ok(proxy_consumer)
+    }
+
+    /// Gets a credential field from the configuration of an auth plugin.
+    pub fn credential(&self, plugin: &str, field: &str) -> Option<&str> {
+        self.inner.plugins.get(plugin)?.get(field)?.as_str()
+    }
+}
+
+/// Returns the identifying credentials of a consumer as `(plugin, value)` pairs.
+pub fn consumer_credentials(
+    consumer: &config::Consumer,
+) -> impl Iterator<Item = (&'static str, &str)> {
+    AUTH_PLUGINS.iter().filter_map(|(plugin, field)| {
+        let value = consumer.plugins.get(*plugin)?.get(*field)?.as_str()?;
+        Some((*plugin, value))
+    })
+}
+
+/// Checks that no two consumers share an identifying credential.
+pub fn check_unique_credentials<'a>(
+    consumers: impl IntoIterator<Item = &'a config::Consumer>,
+) -> Result<()> {
+    let mut owners: HashMap<(&str, &str), &str> = HashMap::new();
+    for consumer in consumers {
+        for credential in consumer_credentials(consumer) {
+            if let Some(owner) = owners.insert(credential, &consumer.username) {
+                if owner != consumer.username {
+                    return Error::e_explain(
+                        ReadError,
+                        format!(
+                            "Consumers {} and {} share the same {} credential",
+                            owner, consumer.username, credential.0
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Global map to store consumers keyed by username, initialized lazily.
+pub static CONSUMER_MAP: Lazy<RwLock<HashMap<String, Arc<ProxyConsumer>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Loads consumers from the given configuration.
+pub fn load_static_consumers(config: &config::Config) -> Result<()> {
+    let proxy_consumers: Vec<Arc<ProxyConsumer>> = config
+        .consumers
+        .iter()
+        .map(|consumer| {
+            log::info!("Configuring Consumer: {}", consumer.username);
+
+            match ProxyConsumer::new_with_plugins(consumer.clone()) {
+                Ok(proxy_consumer) => Ok(Arc::new(proxy_consumer)),
+                Err(e) => {
+                    log::error!("Failed to configure Consumer {}: {}", consumer.username, e);
+                    Err(e)
+                }
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    CONSUMER_MAP.reload_resource(proxy_consumers);
+    reload_credential_index();
+
+    Ok(())
+}
+
+pub fn consumer_fetch(username: &str) -> Option<Arc<ProxyConsumer>> {
+    match CONSUMER_MAP.get(username) {
+        Some(consumer) => Some(consumer),
+        None => {
+            log::warn!("Consumer with username '{}' not found", username);
+            None
+        }
+    }
+}
+
+/// Consumers keyed by auth plugin and the SHA-256 digest of their identifying
+/// credential, so that looking up a secret never compares it byte by byte.
+type CredentialIndex = HashMap<(&'static str, [u8; 32]), Arc<ProxyConsumer>>;
+
+static CREDENTIAL_INDEX: Lazy<ArcSwap<CredentialIndex>> =
+    Lazy::new(|| ArcSwap::new(Arc::new(HashMap::new())));
+
+/// Rebuilds the credential index from `CONSUMER_MAP`.
+///
+/// Duplicate credentials are rejected when consumers are configured, should some
+/// still slip through the consumer with the smallest username keeps them.
+pub fn reload_credential_index() {
+    // The map stays locked until the index is stored, so a concurrent reload can't
+    // store an index built from an older map
+    let map = CONSUMER_MAP.read().unwrap();
+    let mut consumers: Vec<&Arc<ProxyConsumer>> = map.values().collect();
+    consumers.sort_by(|a, b| a.inner.username.cmp(&b.inner.username));
+
+    let mut index = CredentialIndex::new();
+    for consumer in consumers {
+        for (plugin, value) in consumer_credentials(&consumer.inner) {
+            let key = (plugin, Sha256::digest(value).into());
+            if let Some(owner) = index.get(&key) {
+                log::error!(
+                    "Consumer {} has the same {} credential as {}, ignoring it",
+                    consumer.inner.username,
+                    plugin,
+                    owner.inner.username
+                );
+                continue;
+            }
+            index.insert(key, consumer.clone());
+        }
+    }
+
+    CREDENTIAL_INDEX.store(Arc::new(index));
+}
+
+/// Finds the consumer identified by a credential of an auth plugin, e.g. the `key`
+/// of `key-auth`.
+pub fn consumer_fetch_by_credential(plugin: &str, value: &str) -> Option<Arc<ProxyConsumer>> {
+    let (plugin, field) = AUTH_PLUGINS.iter().find(|(name, _)| *name == plugin)?;
+    let consumer = CREDENTIAL_INDEX
+        .load()
+        .get(&(*plugin, Sha256::digest(value).into()))
+        .cloned()?;
+
+    consumer
+        .credential(plugin, field)
+        .is_some_and(|credential| constant_time_eq(credential.as_bytes(), value.as_bytes()))
+        .then_some(consumer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn consumer(username: &str, plugins: &str) -> config::Consumer {
+        config::Consumer {
+            username: username.to_string(),
+            plugins: serde_yaml::from_str(plugins).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_unique_credentials() {
+        let jack = consumer("jack", "key-auth: {key: jack-key}");
+        let jill = consumer("jill", "key-auth: {key: jill-key}");
+        assert!(check_unique_credentials([&jack, &jill]).is_ok());
+
+        // The same value in another auth plugin is a different credential
+        let jwt = consumer("jwt", "jwt-auth: {key: jack-key, secret: secret}");
+        assert!(check_unique_credentials([&jack, &jwt]).is_ok());
+
+        let copy = consumer("copy", "key-auth: {key: jack-key}");
+        assert!(check_unique_credentials([&jack, &copy]).is_err());
+
+        let basic = consumer("basic", "basic-auth: {username: jack, password: a}");
+        let basic_copy = consumer("basic-copy", "basic-auth: {username: jack, password: b}");
+        assert!(check_unique_credentials([&basic, &basic_copy]).is_err());
+    }
+
+    #[test]
+    fn test_consumer_fetch_by_credential() {
+        for (username, plugins) in [
+            ("index-a", "key-auth: {key: index-dup}"),
+            ("index-b", "key-auth: {key: index-dup}"),
+            (
+                "index-c",
+                "hmac-auth: {access_key: index-ak, secret_key: sk}",
+            ),
+        ] {
+            let proxy_consumer = ProxyConsumer::new_with_plugins(consumer(username, plugins));
+            CONSUMER_MAP.insert(Arc::new(proxy_consumer.unwrap()));
+        }
+        reload_credential_index();
+
+        // A duplicate slipping through resolves to the smallest username
+        let found = consumer_fetch_by_credential(key_auth::PLUGIN_NAME, "index-dup").unwrap();
+        assert_eq!(found.inner.username, "index-a");
+
+        let found = consumer_fetch_by_credential(hmac_auth::PLUGIN_NAME, "index-ak").unwrap();
+        assert_eq!(found.inner.username, "index-c");
+
+        assert!(consumer_fetch_by_credential(key_auth::PLUGIN_NAME, "index-ak").is_none());
+        assert!(consumer_fetch_by_credential(key_auth::PLUGIN_NAME, "index-du").is_none());
+        assert!(consumer_fetch_by_credential("unknown", "index-dup").is_none());
+
+        CONSUMER_MAP.remove("index-b");
+        reload_credential_index();
+        let found = consumer_fetch_by_credential(key_auth::PLUGIN_NAME, "index-dup").unwrap();
+        assert_eq!(found.inner.username, "index-a");
+    }
+}
@@ -1,48 +1,250 @@
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{hash_map::DefaultHasher, BTreeSet, HashMap};
+use std::fs;
+use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant, SystemTime};
 
 use async_trait::async_trait;
 use futures::future::join_all;
-use hickory_resolver::TokioAsyncResolver;
+use hickory_resolver::{
+    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    proto::rr::Name,
+    system_conf::read_system_conf,
+    TokioAsyncResolver,
+};
+use http::{header, StatusCode};
 use once_cell::sync::OnceCell;
 use pingora::{protocols::ALPN, upstreams::peer::HttpPeer};
-use pingora_error::{Error, ErrorType::InternalError, OrErr, Result};
+use pingora_core::{
+    connectors::http::Connector,
+    protocols::http::client::HttpSession,
+    tls::{pkey::PKey, x509::X509},
+    utils::tls::CertKey,
+};
+use pingora_error::{Error, ErrorType::InternalError, OkOrErr, OrErr, Result};
+use pingora_http::RequestHeader;
 use pingora_load_balancing::{
     discovery::{ServiceDiscovery, Static},
     Backend,
 };
 use regex::Regex;
+use serde::Deserialize;
+use tokio::task::AbortHandle;
+
+use crate::config::{
+    self, Discovery, DiscoveryType, Dns, Upstream, UpstreamNodes, UpstreamPassHost, UpstreamScheme,
+    UpstreamTls,
+};
+
+/// Priority of a backend, stored in its extensions.
+///
+/// Backends of a lower priority are only selected when all backends of a
+/// higher priority are unavailable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BackendPriority(pub i32);
+
+/// Gets the priority of a backend.
+pub fn backend_priority(backend: &Backend) -> i32 {
+    backend
+        .ext
+        .get::<BackendPriority>()
+        .map_or(0, |priority| priority.0)
+}
+
+/// Gets the key of a backend in the enablement map returned by discovery, the same
+/// as the crate private `Backend::hash_key`.
+fn backend_hash_key(backend: &Backend) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    backend.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Backends and their enablement, as returned by `ServiceDiscovery::discover`.
+type Discovered = (BTreeSet<Backend>, HashMap<u64, bool>);
+
+/// TLS settings applied to the peers of an upstream.
+#[derive(Clone)]
+pub struct PeerTls {
+    client_cert_key: Option<Arc<CertKey>>,
+    ca: Option<Arc<Box<[X509]>>>,
+    verify: bool,
+    sni: Option<String>,
+}
 
-use crate::config::{Upstream, UpstreamPassHost, UpstreamScheme};
+impl PeerTls {
+    fn apply(&self, peer: &mut HttpPeer) {
+        peer.client_cert_key = self.client_cert_key.clone();
+        peer.options.ca = self.ca.clone();
+        peer.options.verify_cert = self.verify;
+        peer.options.verify_hostname = self.verify;
+        if let Some(sni) = self.sni.as_ref() {
+            peer.sni = sni.clone();
+        }
+    }
+}
+
+impl TryFrom<&UpstreamTls> for PeerTls {
+    type Error = Box<Error>;
+
+    fn try_from(tls: &UpstreamTls) -> Result<Self> {
+        let client_cert_key = match (tls.client_cert.as_ref(), tls.client_key.as_ref()) {
+            (Some(cert), Some(key)) => {
+                let certs = X509::stack_from_pem(cert.as_bytes())
+                    .or_err(InternalError, "Invalid upstream tls client_cert")?;
+                // CertKey::new panics on an empty chain
+                if certs.is_empty() {
+                    return Error::e_explain(
+                        InternalError,
+                        "No certificate in upstream tls client_cert",
+                    );
+                }
+                let key = PKey::private_key_from_pem(key.as_bytes())
+                    .or_err(InternalError, "Invalid upstream tls client_key")?;
+                Some(Arc::new(CertKey::new(certs, key)))
+            }
+            _ => None,
+        };
+
+        let ca = tls
+            .ca
+            .as_ref()
+            .map(|ca| X509::stack_from_pem(ca.as_bytes()))
+            .transpose()
+            .or_err(InternalError, "Invalid upstream tls ca")?
+            .map(|certs| {
+                if certs.is_empty() {
+                    return Error::e_explain(InternalError, "No certificate in upstream tls ca");
+                }
+                Ok(Arc::new(certs.into_boxed_slice()))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            client_cert_key,
+            ca,
+            verify: tls.verify,
+            sni: tls.sni.clone(),
+        })
+    }
+}
 
 static GLOBAL_RESOLVER: OnceCell<Arc<TokioAsyncResolver>> = OnceCell::new();
 
+static DNS_CONFIG: OnceCell<Dns> = OnceCell::new();
+
+static DISCOVERY_CONFIG: OnceCell<Discovery> = OnceCell::new();
+
+/// Sets the service discovery settings, must be called before upstreams are loaded.
+pub fn set_discovery_config(discovery: Discovery) {
+    if DISCOVERY_CONFIG.set(discovery).is_err() {
+        log::warn!("Discovery config is already set");
+    }
+}
+
+fn discovery_config() -> &'static Discovery {
+    DISCOVERY_CONFIG.get_or_init(Discovery::default)
+}
+
+/// Sets the resolver settings, must be called before upstreams are loaded.
+pub fn set_dns_config(dns: Dns) {
+    if DNS_CONFIG.set(dns).is_err() {
+        log::warn!("DNS config is already set");
+    }
+}
+
+fn dns_config() -> &'static Dns {
+    DNS_CONFIG.get_or_init(Dns::default)
+}
+
 fn get_global_resolver() -> Arc<TokioAsyncResolver> {
     GLOBAL_RESOLVER
-        .get_or_init(|| Arc::new(TokioAsyncResolver::tokio_from_system_conf().unwrap()))
+        .get_or_init(|| Arc::new(build_resolver(dns_config())))
         .clone()
 }
 
+/// Builds the resolver from the system configuration, or from the configured
+/// nameservers if any.
+fn build_resolver(dns: &Dns) -> TokioAsyncResolver {
+    let (mut config, mut opts) = if dns.nameservers.is_empty() {
+        read_system_conf().unwrap_or_else(|e| {
+            log::warn!(
+                "Failed to read system DNS configuration, using defaults: {}",
+                e
+            );
+            (ResolverConfig::default(), ResolverOpts::default())
+        })
+    } else {
+        let mut config = ResolverConfig::new();
+        for addr in dns
+            .nameservers
+            .iter()
+            .filter_map(|ns| Dns::parse_nameserver(ns))
+        {
+            config.add_name_server(NameServerConfig::new(addr, Protocol::Udp));
+            config.add_name_server(NameServerConfig::new(addr, Protocol::Tcp));
+        }
+        (config, ResolverOpts::default())
+    };
+
+    for domain in dns.search.iter() {
+        match Name::from_str(domain) {
+            Ok(name) => config.add_search(name),
+            Err(e) => log::warn!("Ignoring invalid DNS search domain {}: {}", domain, e),
+        }
+    }
+    opts.timeout = Duration::from_secs(dns.timeout);
+
+    TokioAsyncResolver::tokio(config, opts)
+}
+
+/// Computes when resolved records are refreshed, following their TTL within
+/// the configured bounds.
+fn refresh_at(valid_until: Instant, now: Instant, dns: &Dns) -> Instant {
+    let ttl = valid_until.saturating_duration_since(now).clamp(
+        Duration::from_secs(dns.min_refresh_interval),
+        Duration::from_secs(dns.max_refresh_interval),
+    );
+    now + ttl
+}
+
+/// Backends resolved by a DNS discovery, reused until their TTL expires.
+struct DnsCache {
+    backends: BTreeSet<Backend>,
+    refresh_at: Instant,
+}
+
 /// DNS-based service discovery.
 ///
 /// Resolves DNS names to IP addresses and creates backends for each resolved IP.
+/// Names starting with an underscore, e.g. `_http._tcp.svc`, are resolved as
+/// SRV records which give the port and weight of each target.
 pub struct DnsDiscovery {
     resolver: Arc<TokioAsyncResolver>,
     name: String,
-    port: u32,
+    port: u16,
 
     scheme: UpstreamScheme,
     weight: u32,
+    priority: i32,
+    tls: Option<PeerTls>,
+    cache: Mutex<Option<DnsCache>>,
 }
 
 impl DnsDiscovery {
     /// Creates a new `DnsDiscovery` instance.
     pub fn new(
         name: String,
-        port: u32,
+        port: u16,
         scheme: UpstreamScheme,
         weight: u32,
+        priority: i32,
+        tls: Option<PeerTls>,
         resolver: Arc<TokioAsyncResolver>,
     ) -> Self {
         Self {
@@ -51,58 +253,1079 @@ impl DnsDiscovery {
             port,
             scheme,
             weight,
+            priority,
+            tls,
+            cache: Mutex::new(None),
         }
     }
+
+    fn is_srv(&self) -> bool {
+        self.name.starts_with('_')
+    }
+
+    /// Resolves the A/AAAA records of the name.
+    async fn resolve_ip(&self) -> Result<(BTreeSet<Backend>, Instant)> {
+        let name = self.name.as_str();
+        let lookup = self
+            .resolver
+            .lookup_ip(name)
+            .await
+            .or_err_with(InternalError, || {
+                format!("DNS discovery failed for domain: {}", name)
+            })?;
+
+        let backends = lookup
+            .iter()
+            .map(|ip| self.new_backend(ip, self.port, self.weight, name))
+            .collect();
+
+        Ok((backends, lookup.valid_until()))
+    }
+
+    /// Resolves the SRV records of the name, then the addresses of their targets.
+    async fn resolve_srv(&self) -> Result<(BTreeSet<Backend>, Instant)> {
+        let name = self.name.as_str();
+        let lookup = self
+            .resolver
+            .srv_lookup(name)
+            .await
+            .or_err_with(InternalError, || {
+                format!("DNS SRV discovery failed for domain: {}", name)
+            })?;
+
+        let mut valid_until = lookup.as_lookup().valid_until();
+        let mut backends = BTreeSet::new();
+        for srv in lookup.iter() {
+            let target = srv.target().to_utf8();
+            let target = target.trim_end_matches('.');
+            let ips = self
+                .resolver
+                .lookup_ip(target)
+                .await
+                .or_err_with(InternalError, || {
+                    format!("DNS discovery failed for SRV target: {}", target)
+                })?;
+
+            valid_until = valid_until.min(ips.valid_until());
+            // A weight of 0 means no preference, all targets still get traffic
+            let weight = (srv.weight() as u32).max(1);
+            backends.extend(
+                ips.iter()
+                    .map(|ip| self.new_backend(ip, srv.port(), weight, target)),
+            );
+        }
+
+        Ok((backends, valid_until))
+    }
+
+    fn new_backend(&self, ip: IpAddr, port: u16, weight: u32, sni: &str) -> Backend {
+        new_backend(
+            SocketAddr::new(ip, port),
+            weight,
+            self.priority,
+            self.scheme,
+            sni.to_string(),
+            self.tls.as_ref(),
+        )
+    }
+}
+
+/// Creates a backend carrying its `HttpPeer` and priority.
+fn new_backend(
+    addr: SocketAddr,
+    weight: u32,
+    priority: i32,
+    scheme: UpstreamScheme,
+    sni: String,
+    peer_tls: Option<&PeerTls>,
+) -> Backend {
+    let addr = addr.to_string();
+
+    // Creating backend
+    let mut backend = Backend::new_with_weight(&addr, weight as _).unwrap();
+
+    // Determine if TLS is needed
+    let tls = matches!(scheme, UpstreamScheme::HTTPS | UpstreamScheme::GRPCS);
+
+    // Create HttpPeer
+    let mut peer = HttpPeer::new(&addr, tls, sni);
+    if matches!(scheme, UpstreamScheme::GRPC | UpstreamScheme::GRPCS) {
+        peer.options.alpn = ALPN::H2;
+    }
+    if let Some(peer_tls) = peer_tls {
+        peer_tls.apply(&mut peer);
+    }
+
+    // Insert HttpPeer into the backend
+    assert!(backend.ext.insert::<HttpPeer>(peer).is_none());
+    backend.ext.insert(BackendPriority(priority));
+
+    backend
+}
+
+/// Default port of nodes without one.
+fn default_port(scheme: UpstreamScheme) -> u16 {
+    match scheme {
+        UpstreamScheme::HTTPS => 443,
+        _ => 80,
+    }
 }
 
 #[async_trait]
 impl ServiceDiscovery for DnsDiscovery {
     /// Discovers backends by resolving DNS names to IP addresses.
+    ///
+    /// Results are cached until their TTL expires, on failure the last
+    /// resolved backends are kept.
     async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
-        let name = self.name.as_str();
-        log::debug!("Resolving DNS for domain: {}", name);
+        if let Some(cache) = self.cache.lock().unwrap().as_ref() {
+            if Instant::now() < cache.refresh_at {
+                return Ok((cache.backends.clone(), HashMap::new()));
+            }
+        }
 
-        let backends: BTreeSet<Backend> = self
-            .resolver
-            .lookup_ip(name)
-            .await
+        log::debug!("Resolving DNS for domain: {}", self.name);
+        let resolved = if self.is_srv() {
+            self.resolve_srv().await
+        } else {
+            self.resolve_ip().await
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        match resolved {
+            Ok((backends, valid_until)) => {
+                *cache = Some(DnsCache {
+                    backends: backends.clone(),
+                    refresh_at: refresh_at(valid_until, Instant::now(), dns_config()),
+                });
+
+                // Return backends and an empty HashMap for now
+                Ok((backends, HashMap::new()))
+            }
+            Err(e) => match cache.as_ref() {
+                Some(cache) => {
+                    log::warn!("{}, keeping the last resolved backends", e);
+                    Ok((cache.backends.clone(), HashMap::new()))
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/// Builds the backends of discovered nodes with the peer settings of their upstream.
+#[derive(Clone)]
+struct BackendBuilder {
+    scheme: UpstreamScheme,
+    rewrite_host: Option<String>,
+    tls: Option<PeerTls>,
+}
+
+impl BackendBuilder {
+    fn new(upstream: &Upstream, tls: Option<PeerTls>) -> Self {
+        Self {
+            scheme: upstream.scheme,
+            rewrite_host: rewrite_host(upstream),
+            tls,
+        }
+    }
+
+    /// Builds the backends of `(address, weight, priority)` nodes, domain
+    /// nodes are resolved.
+    async fn build(&self, nodes: &[(String, u32, i32)]) -> Result<BTreeSet<Backend>> {
+        let mut backends = BTreeSet::new();
+
+        for (addr, weight, priority) in nodes {
+            let (host, port) = parse_host_and_port(addr)?;
+            let port = port.unwrap_or(default_port(self.scheme));
+
+            if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+                let sni = self.rewrite_host.clone().unwrap_or_else(|| host.clone());
+                backends.insert(new_backend(
+                    SocketAddr::new(ip, port),
+                    *weight,
+                    *priority,
+                    self.scheme,
+                    sni,
+                    self.tls.as_ref(),
+                ));
+                continue;
+            }
+
+            let ips = get_global_resolver()
+                .lookup_ip(host.as_str())
+                .await
+                .or_err_with(InternalError, || {
+                    format!("DNS discovery failed for domain: {}", host)
+                })?;
+            backends.extend(ips.iter().map(|ip| {
+                new_backend(
+                    SocketAddr::new(ip, port),
+                    *weight,
+                    *priority,
+                    self.scheme,
+                    host.clone(),
+                    self.tls.as_ref(),
+                )
+            }));
+        }
+
+        Ok(backends)
+    }
+}
+
+/// Nodes of a file discovery read at a given modification time.
+struct FileCache {
+    modified: SystemTime,
+    backends: BTreeSet<Backend>,
+}
+
+/// File-based service discovery.
+///
+/// Reads the nodes of a service from a YAML or JSON file mapping service names
+/// to nodes, the file is read again whenever its modification time changes.
+pub struct FileDiscovery {
+    path: PathBuf,
+    service_name: String,
+    builder: BackendBuilder,
+    cache: Mutex<Option<FileCache>>,
+}
+
+impl FileDiscovery {
+    /// Creates a new `FileDiscovery` instance.
+    pub fn new(
+        conf: &config::FileDiscovery,
+        service_name: String,
+        upstream: &Upstream,
+        tls: Option<PeerTls>,
+    ) -> Self {
+        Self {
+            path: PathBuf::from(&conf.path),
+            service_name,
+            builder: BackendBuilder::new(upstream, tls),
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// Gets the modification time of the file.
+    fn modified(&self) -> Result<SystemTime> {
+        fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
             .or_err_with(InternalError, || {
-                format!("DNS discovery failed for domain: {}", name)
-            })?
+                format!("Failed to read discovery file {}", self.path.display())
+            })
+    }
+
+    /// Reads the file and builds the backends of the service.
+    async fn load(&self) -> Result<(SystemTime, BTreeSet<Backend>)> {
+        let modified = self.modified()?;
+        let content = fs::read_to_string(&self.path).or_err_with(InternalError, || {
+            format!("Failed to read discovery file {}", self.path.display())
+        })?;
+        let nodes = parse_service_nodes(&content, &self.service_name)?;
+
+        Ok((modified, self.builder.build(&nodes.to_weighted()).await?))
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for FileDiscovery {
+    /// Discovers backends from the file, the last read backends are kept while
+    /// the file is unchanged or invalid.
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        let modified = self.modified().ok();
+        if let Some(cache) = self.cache.lock().unwrap().as_ref() {
+            if modified == Some(cache.modified) {
+                return Ok((cache.backends.clone(), HashMap::new()));
+            }
+        }
+
+        log::debug!(
+            "Reading nodes of service {} from {}",
+            self.service_name,
+            self.path.display()
+        );
+        let loaded = self.load().await;
+
+        let mut cache = self.cache.lock().unwrap();
+        match loaded {
+            Ok((modified, backends)) => {
+                *cache = Some(FileCache {
+                    modified,
+                    backends: backends.clone(),
+                });
+                Ok((backends, HashMap::new()))
+            }
+            Err(e) => match cache.as_ref() {
+                Some(cache) => {
+                    log::warn!("{}, keeping the last discovered backends", e);
+                    Ok((cache.backends.clone(), HashMap::new()))
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/// Parses the nodes of a service from the content of a discovery file.
+///
+/// A service missing from the file has no nodes.
+fn parse_service_nodes(content: &str, service_name: &str) -> Result<UpstreamNodes> {
+    let mut services: HashMap<String, UpstreamNodes> =
+        serde_yaml::from_str(content).or_err(InternalError, "Invalid discovery file")?;
+
+    let nodes = services.remove(service_name).unwrap_or_else(|| {
+        log::warn!("Service {} not found in discovery file", service_name);
+        UpstreamNodes::default()
+    });
+
+    Upstream::validate_nodes(&nodes).or_err_with(InternalError, || {
+        format!(
+            "Invalid nodes of service {} in discovery file",
+            service_name
+        )
+    })?;
+
+    Ok(nodes)
+}
+
+/// How often the load balancer picks up the backends of watch based discoveries.
+const WATCH_UPDATE_FREQUENCY: Duration = Duration::from_secs(1);
+
+/// Seconds a Kubernetes list request may take.
+const KUBERNETES_LIST_TIMEOUT: u64 = 30;
+
+/// A healthy service instance returned by `/v1/health/service/<name>`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulServiceEntry {
+    node: ConsulNode,
+    service: ConsulService,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulNode {
+    address: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulService {
+    /// Falls back to the node address when empty
+    #[serde(default)]
+    address: String,
+    port: u16,
+    weights: Option<ConsulWeights>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulWeights {
+    passing: u32,
+}
+
+/// Parses the healthy instances of a service into `(address, weight, priority)` nodes.
+fn parse_consul_nodes(body: &[u8]) -> Result<Vec<(String, u32, i32)>> {
+    let entries: Vec<ConsulServiceEntry> =
+        serde_json::from_slice(body).or_err(InternalError, "Invalid Consul response")?;
+
+    Ok(entries
+        .into_iter()
+        .map(|entry| {
+            let host = if entry.service.address.is_empty() {
+                entry.node.address
+            } else {
+                entry.service.address
+            };
+            let host = if host.contains(':') {
+                format!("[{}]", host)
+            } else {
+                host
+            };
+            let weight = entry.service.weights.map_or(1, |weights| weights.passing);
+            (format!("{}:{}", host, entry.service.port), weight, 0)
+        })
+        .collect())
+}
+
+/// Sends a GET request to an HTTP API server, returning the session once a
+/// `200 OK` response header is read.
+async fn http_get(
+    connector: &Connector,
+    server: &str,
+    path: &str,
+    headers: &[(&'static str, String)],
+    peer_tls: Option<&PeerTls>,
+    read_timeout: Duration,
+) -> Result<HttpSession> {
+    let uri = server
+        .parse::<http::Uri>()
+        .or_err_with(InternalError, || format!("Invalid server {}", server))?;
+    let tls = uri.scheme_str() == Some("https");
+    let host = uri.host().unwrap_or_default();
+    let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+
+    let addr = format!("{}:{}", host, port);
+    let mut peer = HttpPeer::new(&addr, tls, host.trim_matches(['[', ']']).to_string());
+    if let Some(peer_tls) = peer_tls {
+        peer_tls.apply(&mut peer);
+    }
+    peer.options.read_timeout = Some(read_timeout);
+
+    let mut req = RequestHeader::build("GET", path.as_bytes(), None)?;
+    req.insert_header(header::HOST, uri.authority().map_or(host, |a| a.as_str()))?;
+    for (name, value) in headers {
+        req.insert_header(*name, value)?;
+    }
+
+    let (mut session, _) = connector.get_http_session(&peer).await?;
+    session.write_request_header(Box::new(req)).await?;
+    session.finish_request_body().await?;
+    session.read_response_header().await?;
+
+    let status = session
+        .response_header()
+        .or_err(InternalError, "Missing response header")?
+        .status;
+    if status != StatusCode::OK {
+        return Error::e_explain(
+            InternalError,
+            format!("{} responded with status {}", server, status),
+        );
+    }
+
+    Ok(session)
+}
+
+/// Reads the whole response body of a session.
+async fn read_body(session: &mut HttpSession) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    while let Some(chunk) = session.read_response_body().await? {
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Gets the index of the next blocking query, which restarts from scratch when
+/// the index goes backwards.
+fn next_consul_index(previous: u64, index: u64) -> u64 {
+    if index < previous {
+        0
+    } else {
+        index
+    }
+}
+
+/// Watches the healthy instances of a service, shared between the discovery
+/// and its background blocking queries.
+struct ConsulWatch {
+    conf: config::ConsulDiscovery,
+    service_name: String,
+    builder: BackendBuilder,
+    connector: Connector,
+    backends: Mutex<Option<BTreeSet<Backend>>>,
+}
+
+impl ConsulWatch {
+    /// Queries the servers in order, blocking until the index changes when
+    /// an index is given.
+    async fn query(&self, index: u64) -> Result<(u64, Vec<(String, u32, i32)>)> {
+        let mut last_error = None;
+        for server in self.conf.servers.iter() {
+            match self.query_server(server, index).await {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    log::debug!("Consul server {} failed: {}", server, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| Error::explain(InternalError, "No Consul server")))
+    }
+
+    async fn query_server(
+        &self,
+        server: &str,
+        index: u64,
+    ) -> Result<(u64, Vec<(String, u32, i32)>)> {
+        let mut path = format!("/v1/health/service/{}?passing=true", self.service_name);
+        if index > 0 {
+            path.push_str(&format!("&index={}&wait={}s", index, self.conf.wait));
+        }
+
+        let headers = self
+            .conf
+            .token
             .iter()
-            .map(|ip| {
-                let addr = SocketAddr::new(ip, self.port as _).to_string();
+            .map(|token| ("X-Consul-Token", token.clone()))
+            .collect::<Vec<_>>();
+        // Consul adds a jitter of up to wait / 16 to blocking queries
+        let read_timeout =
+            Duration::from_secs(self.conf.wait + self.conf.wait / 16 + self.conf.retry_delay);
+
+        let mut session =
+            http_get(&self.connector, server, &path, &headers, None, read_timeout).await?;
 
-                // Creating backend
-                let mut backend = Backend::new_with_weight(&addr, self.weight as _).unwrap();
+        let new_index = session
+            .response_header()
+            .and_then(|resp| resp.headers.get("X-Consul-Index"))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or_default();
+        let body = read_body(&mut session).await?;
 
-                // Determine if TLS is needed
-                let tls = matches!(self.scheme, UpstreamScheme::HTTPS | UpstreamScheme::GRPCS);
+        Ok((
+            next_consul_index(index, new_index),
+            parse_consul_nodes(&body)?,
+        ))
+    }
 
-                // Create HttpPeer
-                let mut peer = HttpPeer::new(&addr, tls, self.name.clone());
-                if matches!(self.scheme, UpstreamScheme::GRPC | UpstreamScheme::GRPCS) {
-                    peer.options.alpn = ALPN::H2;
+    /// Queries the instances and stores their backends, returning the next index.
+    async fn update(&self, index: u64) -> Result<u64> {
+        let (index, nodes) = self.query(index).await?;
+        let backends = self.builder.build(&nodes).await?;
+        *self.backends.lock().unwrap() = Some(backends);
+        Ok(index)
+    }
+}
+
+/// Runs blocking queries until aborted by dropping the discovery.
+async fn watch_consul(watch: Arc<ConsulWatch>, mut index: u64) {
+    loop {
+        match watch.update(index).await {
+            Ok(next) => index = next,
+            Err(e) => {
+                log::warn!(
+                    "Consul discovery failed for service {}: {}",
+                    watch.service_name,
+                    e
+                );
+                tokio::time::sleep(Duration::from_secs(watch.conf.retry_delay)).await;
+            }
+        }
+    }
+}
+
+/// Consul-based service discovery.
+///
+/// Watches the passing instances of a service from the Consul health API with
+/// blocking queries in the background, the load balancer only reads the last
+/// watched backends.
+pub struct ConsulDiscovery {
+    watch: Arc<ConsulWatch>,
+    started: AtomicBool,
+    task: Mutex<Option<AbortHandle>>,
+}
+
+impl ConsulDiscovery {
+    /// Creates a new `ConsulDiscovery` instance.
+    pub fn new(
+        conf: &config::ConsulDiscovery,
+        service_name: String,
+        upstream: &Upstream,
+        tls: Option<PeerTls>,
+    ) -> Self {
+        Self {
+            watch: Arc::new(ConsulWatch {
+                conf: conf.clone(),
+                service_name,
+                builder: BackendBuilder::new(upstream, tls),
+                connector: Connector::new(None),
+                backends: Mutex::new(None),
+            }),
+            started: AtomicBool::new(false),
+            task: Mutex::new(None),
+        }
+    }
+}
+
+impl Drop for ConsulDiscovery {
+    /// Aborts the background queries, a pending blocking query included.
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get_mut().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for ConsulDiscovery {
+    /// Returns the last watched backends, the watch starts with the first call.
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        if !self.started.swap(true, Ordering::Relaxed) {
+            let index = self.watch.update(0).await.unwrap_or_else(|e| {
+                log::warn!(
+                    "Consul discovery failed for service {}: {}",
+                    self.watch.service_name,
+                    e
+                );
+                0
+            });
+            let task = tokio::spawn(watch_consul(self.watch.clone(), index));
+            *self.task.lock().unwrap() = Some(task.abort_handle());
+        }
+
+        let backends =
+            self.watch
+                .backends
+                .lock()
+                .unwrap()
+                .clone()
+                .or_err_with(InternalError, || {
+                    format!(
+                        "No instances watched from Consul yet for service {}",
+                        self.watch.service_name
+                    )
+                })?;
+        Ok((backends, HashMap::new()))
+    }
+}
+
+/// A `namespace/name:port` service of the Kubernetes discovery.
+#[derive(Debug, PartialEq, Eq)]
+struct KubernetesService {
+    namespace: String,
+    name: String,
+    /// Port name or target port number, the first port of the slices when missing
+    port: Option<String>,
+}
+
+impl FromStr for KubernetesService {
+    type Err = Box<Error>;
+
+    fn from_str(service: &str) -> Result<Self> {
+        let invalid = || {
+            format!(
+                "Invalid Kubernetes service name {}, expected namespace/name:port",
+                service
+            )
+        };
+        let (namespace, rest) = service
+            .split_once('/')
+            .or_err_with(InternalError, invalid)?;
+        let (name, port) = match rest.split_once(':') {
+            Some((name, port)) => (name, Some(port.to_string())),
+            None => (rest, None),
+        };
+
+        if namespace.is_empty() || name.is_empty() || port.as_deref() == Some("") {
+            return Error::e_explain(InternalError, invalid());
+        }
+
+        Ok(Self {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+            port,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMeta {
+    #[serde(default)]
+    name: String,
+    resource_version: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointSliceList {
+    metadata: ObjectMeta,
+    items: Option<Vec<EndpointSlice>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointSlice {
+    metadata: ObjectMeta,
+    endpoints: Option<Vec<Endpoint>>,
+    ports: Option<Vec<EndpointPort>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Endpoint {
+    addresses: Vec<String>,
+    conditions: Option<EndpointConditions>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointConditions {
+    ready: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EndpointPort {
+    name: Option<String>,
+    port: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    object: serde_json::Value,
+}
+
+/// Applies a watch event to the slices keyed by name, returning the resource
+/// version it carries.
+fn apply_watch_event(
+    line: &[u8],
+    slices: &mut HashMap<String, EndpointSlice>,
+) -> Result<Option<String>> {
+    let event: WatchEvent =
+        serde_json::from_slice(line).or_err(InternalError, "Invalid Kubernetes watch event")?;
+    if event.kind == "ERROR" {
+        // e.g. 410 Gone once the resource version is too old, the slices are listed again
+        return Error::e_explain(
+            InternalError,
+            format!("Kubernetes watch failed: {}", event.object),
+        );
+    }
+
+    let slice: EndpointSlice = serde_json::from_value(event.object)
+        .or_err(InternalError, "Invalid Kubernetes EndpointSlice")?;
+    let version = slice.metadata.resource_version.clone();
+    match event.kind.as_str() {
+        "ADDED" | "MODIFIED" => {
+            slices.insert(slice.metadata.name.clone(), slice);
+        }
+        "DELETED" => {
+            slices.remove(&slice.metadata.name);
+        }
+        // BOOKMARK only moves the resource version forward
+        _ => {}
+    }
+
+    Ok(version)
+}
+
+/// Gets the endpoints of a service port with their readiness, an endpoint
+/// listed by several slices is ready when any of them says so.
+fn slice_endpoints(
+    slices: &HashMap<String, EndpointSlice>,
+    port: Option<&str>,
+) -> HashMap<SocketAddr, bool> {
+    let mut endpoints = HashMap::new();
+
+    for slice in slices.values() {
+        let ports = slice.ports.as_deref().unwrap_or_default();
+        let slice_port = match port {
+            Some(port) => ports.iter().find(|p| {
+                p.name.as_deref() == Some(port) || p.port.is_some_and(|n| n.to_string() == port)
+            }),
+            None => ports.first(),
+        };
+        let Some(slice_port) = slice_port.and_then(|p| p.port) else {
+            continue;
+        };
+
+        for endpoint in slice.endpoints.as_deref().unwrap_or_default() {
+            // A missing condition means ready
+            let ready = endpoint
+                .conditions
+                .as_ref()
+                .and_then(|conditions| conditions.ready)
+                .unwrap_or(true);
+            for address in endpoint.addresses.iter() {
+                if let Ok(ip) = address.parse::<IpAddr>() {
+                    *endpoints
+                        .entry(SocketAddr::new(ip, slice_port))
+                        .or_insert(false) |= ready;
+                }
+            }
+        }
+    }
+
+    endpoints
+}
+
+/// Gets the in-cluster API server from the environment of the pod.
+fn in_cluster_server() -> Option<String> {
+    let host = std::env::var("KUBERNETES_SERVICE_HOST").ok()?;
+    let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host
+    };
+    Some(format!("https://{}:{}", host, port))
+}
+
+/// List-watches the EndpointSlices of a service, shared between the discovery
+/// and its background watch requests.
+struct KubernetesWatch {
+    conf: config::KubernetesDiscovery,
+    server: String,
+    peer_tls: Option<PeerTls>,
+    service: KubernetesService,
+    builder: BackendBuilder,
+    connector: Connector,
+    discovered: Mutex<Option<Discovered>>,
+}
+
+impl KubernetesWatch {
+    fn path(&self, query: &str) -> String {
+        format!(
+            "/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices?labelSelector=kubernetes.io%2Fservice-name%3D{}{}",
+            self.service.namespace, self.service.name, query
+        )
+    }
+
+    /// Reads the token on each request as projected tokens are rotated.
+    fn headers(&self) -> Vec<(&'static str, String)> {
+        match fs::read_to_string(&self.conf.token_file) {
+            Ok(token) => vec![("Authorization", format!("Bearer {}", token.trim()))],
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Lists the slices of the service, returning the resource version to watch from.
+    async fn list(&self, slices: &mut HashMap<String, EndpointSlice>) -> Result<String> {
+        let mut session = http_get(
+            &self.connector,
+            &self.server,
+            &self.path(""),
+            &self.headers(),
+            self.peer_tls.as_ref(),
+            Duration::from_secs(self.conf.retry_delay + KUBERNETES_LIST_TIMEOUT),
+        )
+        .await?;
+        let body = read_body(&mut session).await?;
+        let list: EndpointSliceList = serde_json::from_slice(&body)
+            .or_err(InternalError, "Invalid Kubernetes EndpointSlice list")?;
+
+        *slices = list
+            .items
+            .unwrap_or_default()
+            .into_iter()
+            .map(|slice| (slice.metadata.name.clone(), slice))
+            .collect();
+        self.publish(slices).await?;
+
+        Ok(list.metadata.resource_version.unwrap_or_default())
+    }
+
+    /// Watches changes of the slices until the watch request times out,
+    /// returning the resource version to watch from next.
+    async fn watch(
+        &self,
+        mut version: String,
+        slices: &mut HashMap<String, EndpointSlice>,
+    ) -> Result<String> {
+        let query = format!(
+            "&watch=true&allowWatchBookmarks=true&resourceVersion={}&timeoutSeconds={}",
+            version, self.conf.watch_timeout
+        );
+        let mut session = http_get(
+            &self.connector,
+            &self.server,
+            &self.path(&query),
+            &self.headers(),
+            self.peer_tls.as_ref(),
+            Duration::from_secs(self.conf.watch_timeout + self.conf.retry_delay),
+        )
+        .await?;
+
+        // Events are streamed as one JSON object per line
+        let mut buf = Vec::new();
+        while let Some(chunk) = session.read_response_body().await? {
+            buf.extend_from_slice(&chunk);
+
+            let mut changed = false;
+            while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buf.drain(..=pos).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
                 }
+                if let Some(next) = apply_watch_event(&line, slices)? {
+                    version = next;
+                }
+                changed = true;
+            }
+
+            if changed {
+                self.publish(slices).await?;
+            }
+        }
+
+        Ok(version)
+    }
 
-                // Insert HttpPeer into the backend
-                assert!(backend.ext.insert::<HttpPeer>(peer).is_none());
+    /// Builds the backends of the slices, readiness is mapped to whether each
+    /// backend is enabled.
+    async fn publish(&self, slices: &HashMap<String, EndpointSlice>) -> Result<()> {
+        let endpoints = slice_endpoints(slices, self.service.port.as_deref());
+        let nodes = endpoints
+            .keys()
+            .map(|addr| (addr.to_string(), 1, 0))
+            .collect::<Vec<_>>();
+        let ready = endpoints
+            .into_iter()
+            .map(|(addr, ready)| (addr.to_string(), ready))
+            .collect::<HashMap<_, _>>();
 
-                backend
+        let backends = self.builder.build(&nodes).await?;
+        let enablement = backends
+            .iter()
+            .map(|backend| {
+                let ready = ready.get(&backend.addr.to_string()).copied();
+                (backend_hash_key(backend), ready.unwrap_or(true))
             })
             .collect();
 
-        // Return backends and an empty HashMap for now
-        Ok((backends, HashMap::new()))
+        *self.discovered.lock().unwrap() = Some((backends, enablement));
+        Ok(())
+    }
+}
+
+/// Runs list and watch requests until aborted by dropping the discovery.
+async fn watch_kubernetes(
+    watch: Arc<KubernetesWatch>,
+    mut version: Option<String>,
+    mut slices: HashMap<String, EndpointSlice>,
+) {
+    loop {
+        let result = match version.take() {
+            Some(version) => watch.watch(version, &mut slices).await,
+            None => watch.list(&mut slices).await,
+        };
+
+        match result {
+            Ok(next) => version = Some(next),
+            Err(e) => {
+                log::warn!(
+                    "Kubernetes discovery failed for service {}/{}: {}",
+                    watch.service.namespace,
+                    watch.service.name,
+                    e
+                );
+                tokio::time::sleep(Duration::from_secs(watch.conf.retry_delay)).await;
+            }
+        }
+    }
+}
+
+/// Kubernetes-based service discovery.
+///
+/// List-watches the EndpointSlices of a service in the background, pods that
+/// are not ready are discovered as disabled backends.
+pub struct KubernetesDiscovery {
+    watch: Arc<KubernetesWatch>,
+    started: AtomicBool,
+    task: Mutex<Option<AbortHandle>>,
+}
+
+impl KubernetesDiscovery {
+    /// Creates a new `KubernetesDiscovery` instance.
+    pub fn new(
+        conf: &config::KubernetesDiscovery,
+        service_name: &str,
+        upstream: &Upstream,
+        tls: Option<PeerTls>,
+    ) -> Result<Self> {
+        let service = service_name.parse::<KubernetesService>()?;
+        let server = conf
+            .server
+            .clone()
+            .or_else(in_cluster_server)
+            .or_err(InternalError, "Kubernetes API server is not configured")?;
+
+        // The CA file is only used for https API servers
+        let peer_tls = match fs::read_to_string(&conf.ca_file) {
+            Ok(ca) if server.starts_with("https://") => Some(PeerTls::try_from(&UpstreamTls {
+                client_cert: None,
+                client_key: None,
+                ca: Some(ca),
+                verify: true,
+                sni: None,
+            })?),
+            _ => None,
+        };
+
+        Ok(Self {
+            watch: Arc::new(KubernetesWatch {
+                conf: conf.clone(),
+                server,
+                peer_tls,
+                service,
+                builder: BackendBuilder::new(upstream, tls),
+                connector: Connector::new(None),
+                discovered: Mutex::new(None),
+            }),
+            started: AtomicBool::new(false),
+            task: Mutex::new(None),
+        })
+    }
+}
+
+impl Drop for KubernetesDiscovery {
+    /// Aborts the background requests, a pending watch included.
+    fn drop(&mut self) {
+        if let Some(task) = self.task.get_mut().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for KubernetesDiscovery {
+    /// Returns the last watched backends, the watch starts with the first call.
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        if !self.started.swap(true, Ordering::Relaxed) {
+            let mut slices = HashMap::new();
+            let version = match self.watch.list(&mut slices).await {
+                Ok(version) => Some(version),
+                Err(e) => {
+                    log::warn!(
+                        "Kubernetes discovery failed for service {}/{}: {}",
+                        self.watch.service.namespace,
+                        self.watch.service.name,
+                        e
+                    );
+                    None
+                }
+            };
+            let task = tokio::spawn(watch_kubernetes(self.watch.clone(), version, slices));
+            *self.task.lock().unwrap() = Some(task.abort_handle());
+        }
+
+        self.watch
+            .discovered
+            .lock()
+            .unwrap()
+            .clone()
+            .or_err_with(InternalError, || {
+                format!(
+                    "No endpoints watched from Kubernetes yet for service {}/{}",
+                    self.watch.service.namespace, self.watch.service.name
+                )
+            })
     }
 }
 
 /// Hybrid service discovery.
 ///
-/// Combines static and DNS-based service discovery.
+/// Combines static, DNS-based and provider service discovery.
 #[derive(Default)]
 pub struct HybridDiscovery {
     discoveries: Vec<Box<dyn ServiceDiscovery + Send + Sync>>,
+    /// How often the backends need to be discovered again, `None` when static
+    update_frequency: Option<Duration>,
+}
+
+impl HybridDiscovery {
+    /// Returns how often the backends need to be discovered again.
+    pub fn update_frequency(&self) -> Option<Duration> {
+        self.update_frequency
+    }
+
+    /// Lowers the update frequency to serve the most demanding discovery.
+    fn set_update_frequency(&mut self, frequency: Duration) {
+        self.update_frequency = Some(
+            self.update_frequency
+                .map_or(frequency, |current| current.min(frequency)),
+        );
+    }
 }
 
 #[async_trait]
@@ -138,50 +1361,42 @@ impl TryFrom<Upstream> for HybridDiscovery {
     fn try_from(upstream: Upstream) -> Result<Self> {
         let mut this = Self::default();
         let mut backends = BTreeSet::new();
+        let tls = upstream.tls.as_ref().map(PeerTls::try_from).transpose()?;
 
         // Process each node in upstream
-        for (addr, weight) in upstream.nodes.iter() {
-            let (host, port) = parse_host_and_port(addr)?;
-            let port = port.unwrap_or(match upstream.scheme {
-                UpstreamScheme::HTTPS => 443,
-                _ => 80,
-            });
+        for (addr, weight, priority) in upstream.nodes.to_weighted() {
+            let (host, port) = parse_host_and_port(&addr)?;
+            let port = port.unwrap_or(default_port(upstream.scheme));
 
             if host.parse::<IpAddr>().is_err() {
                 // It's a domain name
                 // Handle DNS discovery for domain names
                 let resolver = get_global_resolver();
-                let discovery = DnsDiscovery::new(host, port, upstream.scheme, *weight, resolver);
+                let discovery = DnsDiscovery::new(
+                    host,
+                    port,
+                    upstream.scheme,
+                    weight,
+                    priority,
+                    tls.clone(),
+                    resolver,
+                );
                 this.discoveries.push(Box::new(discovery));
+                this.set_update_frequency(Duration::from_secs(dns_config().min_refresh_interval));
             } else {
                 // It's an IP address
                 // Handle backend creation for IP addresses
-                let addr = &SocketAddr::new(host.parse::<IpAddr>().unwrap(), port as _).to_string();
-                let mut backend = Backend::new_with_weight(addr, *weight as _).unwrap();
+                let addr = SocketAddr::new(host.parse::<IpAddr>().unwrap(), port);
+                let sni = rewrite_host(&upstream).unwrap_or_else(|| host.to_string());
 
-                let tls = matches!(
+                backends.insert(new_backend(
+                    addr,
+                    weight,
+                    priority,
                     upstream.scheme,
-                    UpstreamScheme::HTTPS | UpstreamScheme::GRPCS
-                );
-                let sni = if upstream.pass_host == UpstreamPassHost::REWRITE {
-                    upstream
-                        .upstream_host
-                        .clone()
-                        .unwrap_or_else(|| host.to_string())
-                } else {
-                    host.to_string()
-                };
-
-                let mut peer = HttpPeer::new(addr, tls, sni);
-                if matches!(
-                    upstream.scheme,
-                    UpstreamScheme::GRPC | UpstreamScheme::GRPCS
-                ) {
-                    peer.options.alpn = ALPN::H2;
-                }
-                assert!(backend.ext.insert::<HttpPeer>(peer).is_none());
-
-                backends.insert(backend);
+                    sni,
+                    tls.as_ref(),
+                ));
             }
         }
 
@@ -189,12 +1404,66 @@ impl TryFrom<Upstream> for HybridDiscovery {
             this.discoveries.push(Static::new(backends));
         }
 
+        if let Some(discovery_type) = upstream.discovery_type {
+            let service_name = upstream.service_name.clone().unwrap_or_default();
+            match discovery_type {
+                DiscoveryType::File => {
+                    let conf = discovery_config().file.as_ref().or_err(
+                        InternalError,
+                        "File discovery is not configured in pingsix.discovery",
+                    )?;
+                    this.discoveries.push(Box::new(FileDiscovery::new(
+                        conf,
+                        service_name,
+                        &upstream,
+                        tls,
+                    )));
+                    this.set_update_frequency(Duration::from_secs(conf.refresh_interval));
+                }
+                DiscoveryType::Consul => {
+                    let conf = discovery_config().consul.as_ref().or_err(
+                        InternalError,
+                        "Consul discovery is not configured in pingsix.discovery",
+                    )?;
+                    this.discoveries.push(Box::new(ConsulDiscovery::new(
+                        conf,
+                        service_name,
+                        &upstream,
+                        tls,
+                    )));
+                    this.set_update_frequency(WATCH_UPDATE_FREQUENCY);
+                }
+                DiscoveryType::Kubernetes => {
+                    let conf = discovery_config().kubernetes.as_ref().or_err(
+                        InternalError,
+                        "Kubernetes discovery is not configured in pingsix.discovery",
+                    )?;
+                    this.discoveries.push(Box::new(KubernetesDiscovery::new(
+                        conf,
+                        &service_name,
+                        &upstream,
+                        tls,
+                    )?));
+                    this.set_update_frequency(WATCH_UPDATE_FREQUENCY);
+                }
+            }
+        }
+
         Ok(this)
     }
 }
 
+/// Gets the host used as SNI of IP nodes when the upstream host is rewritten.
+fn rewrite_host(upstream: &Upstream) -> Option<String> {
+    if upstream.pass_host == UpstreamPassHost::REWRITE {
+        upstream.upstream_host.clone()
+    } else {
+        None
+    }
+}
+
 /// Parses a host and port from a string.
-fn parse_host_and_port(addr: &str) -> Result<(String, Option<u32>)> {
+fn parse_host_and_port(addr: &str) -> Result<(String, Option<u16>)> {
     let re = Regex::new(r"^(?:\[(.+?)\]|([^:]+))(?::(\d+))?$").unwrap();
 
     let caps = match re.captures(addr) {
@@ -205,7 +1474,7 @@ fn parse_host_and_port(addr: &str) -> Result<(String, Option<u32>)> {
     let host = caps.get(1).or(caps.get(2)).unwrap().as_str();
     let port_opt = caps.get(3).map(|p| p.as_str());
     let port = port_opt
-        .map(|p| p.parse::<u32>())
+        .map(|p| p.parse::<u16>())
         .transpose()
         .or_err_with(InternalError, || "Invalid port")?;
 
@@ -221,7 +1490,415 @@ fn parse_host_and_port(addr: &str) -> Result<(String, Option<u32>)> {
 
 #[cfg(test)]
 mod tests {
-    use super::parse_host_and_port;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use pingora_core::connectors::http::Connector;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{
+        apply_watch_event, next_consul_index, parse_consul_nodes, parse_host_and_port,
+        parse_service_nodes, refresh_at, slice_endpoints, watch_kubernetes, BackendBuilder,
+        ConsulDiscovery, ConsulWatch, EndpointSliceList, KubernetesService, KubernetesWatch,
+        PeerTls, ServiceDiscovery,
+    };
+    use crate::config::{self, Dns, UpstreamScheme, UpstreamTls};
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            headers,
+            body.len(),
+            body
+        )
+    }
+
+    /// Serves the responses in order, one per request, then leaves the next
+    /// requests pending. Returns the server address and the requested paths.
+    async fn mock_server(responses: Vec<String>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = format!("http://{}", listener.local_addr().unwrap());
+        let paths = Arc::new(Mutex::new(Vec::new()));
+
+        let requested = paths.clone();
+        tokio::spawn(async move {
+            let mut responses = responses.into_iter();
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut buf = [0; 1024];
+                    let n = stream.read(&mut buf).await.unwrap();
+                    head.extend_from_slice(&buf[..n]);
+                }
+                let head = String::from_utf8(head).unwrap();
+                let path = head.split(' ').nth(1).unwrap().to_string();
+                requested.lock().unwrap().push(path);
+
+                match responses.next() {
+                    Some(response) => stream.write_all(response.as_bytes()).await.unwrap(),
+                    None => {
+                        tokio::spawn(async move {
+                            let _stream = stream;
+                            std::future::pending::<()>().await
+                        });
+                    }
+                }
+            }
+        });
+
+        (server, paths)
+    }
+
+    async fn wait_requests(paths: &Mutex<Vec<String>>, count: usize) {
+        for _ in 0..500 {
+            if paths.lock().unwrap().len() >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!(
+            "expected {} requests, got {:?}",
+            count,
+            paths.lock().unwrap()
+        );
+    }
+
+    fn builder() -> BackendBuilder {
+        BackendBuilder {
+            scheme: UpstreamScheme::HTTP,
+            rewrite_host: None,
+            tls: None,
+        }
+    }
+
+    #[test]
+    fn test_peer_tls_empty_stacks() {
+        let tls = UpstreamTls {
+            client_cert: None,
+            client_key: None,
+            ca: None,
+            verify: true,
+            sni: None,
+        };
+        assert!(PeerTls::try_from(&tls).is_ok());
+
+        // Without validation an empty chain must be an error, not a panic
+        let empty_cert = UpstreamTls {
+            client_cert: Some("not a certificate".to_string()),
+            client_key: Some("not a key".to_string()),
+            ..tls.clone()
+        };
+        assert!(PeerTls::try_from(&empty_cert).is_err());
+
+        let empty_ca = UpstreamTls {
+            ca: Some(String::new()),
+            ..tls
+        };
+        assert!(PeerTls::try_from(&empty_ca).is_err());
+    }
+
+    #[test]
+    fn test_dns_refresh_at() {
+        let dns = Dns::default();
+        let now = Instant::now();
+
+        // The TTL is followed within the configured bounds
+        let valid_until = now + Duration::from_secs(60);
+        assert_eq!(refresh_at(valid_until, now, &dns), valid_until);
+        assert_eq!(refresh_at(now, now, &dns), now + Duration::from_secs(5));
+        assert_eq!(
+            refresh_at(now + Duration::from_secs(86400), now, &dns),
+            now + Duration::from_secs(300)
+        );
+    }
+
+    #[test]
+    fn test_parse_service_nodes() {
+        let content = r#"
+user-service:
+  "127.0.0.1:1980": 1
+order-service:
+  - host: 10.0.0.1
+    port: 8080
+    weight: 2
+    priority: -1
+"#;
+        let nodes = parse_service_nodes(content, "order-service").unwrap();
+        assert_eq!(
+            nodes.to_weighted(),
+            vec![("10.0.0.1:8080".to_string(), 2, -1)]
+        );
+
+        // JSON files are read as well
+        let nodes =
+            parse_service_nodes(r#"{"user-service": {"[::1]:80": 3}}"#, "user-service").unwrap();
+        assert_eq!(nodes.to_weighted(), vec![("[::1]:80".to_string(), 3, 0)]);
+
+        assert!(parse_service_nodes(content, "missing").unwrap().is_empty());
+        assert!(parse_service_nodes(r#"{"svc": {"not a host": 1}}"#, "svc").is_err());
+        assert!(parse_service_nodes("- invalid", "svc").is_err());
+    }
+
+    #[test]
+    fn test_parse_consul_nodes() {
+        let body = br#"[
+            {
+                "Node": {"Node": "node-1", "Address": "10.0.0.1"},
+                "Service": {"ID": "web-1", "Address": "", "Port": 8080, "Weights": {"Passing": 3, "Warning": 1}}
+            },
+            {
+                "Node": {"Node": "node-2", "Address": "10.0.0.2"},
+                "Service": {"ID": "web-2", "Address": "2001:db8::2", "Port": 8080}
+            }
+        ]"#;
+        assert_eq!(
+            parse_consul_nodes(body).unwrap(),
+            vec![
+                ("10.0.0.1:8080".to_string(), 3, 0),
+                ("[2001:db8::2]:8080".to_string(), 1, 0),
+            ]
+        );
+        assert!(parse_consul_nodes(b"{}").is_err());
+
+        // Blocking queries restart when the index goes backwards
+        assert_eq!(next_consul_index(0, 12), 12);
+        assert_eq!(next_consul_index(12, 15), 15);
+        assert_eq!(next_consul_index(15, 3), 0);
+    }
+
+    #[tokio::test]
+    async fn test_consul_watch() {
+        let nodes = |addr: &str| {
+            format!(
+                r#"[{{"Node": {{"Node": "node-1", "Address": "{}"}}, "Service": {{"ID": "web-1", "Port": 8080}}}}]"#,
+                addr
+            )
+        };
+        let (server, paths) = mock_server(vec![
+            response("200 OK", "X-Consul-Index: 5\r\n", &nodes("10.0.0.1")),
+            response("200 OK", "X-Consul-Index: 3\r\n", &nodes("10.0.0.2")),
+            response("500 Internal Server Error", "", ""),
+        ])
+        .await;
+        let conf: config::ConsulDiscovery =
+            serde_yaml::from_str(&format!("servers: [\"{}\"]", server)).unwrap();
+        let watch = ConsulWatch {
+            conf,
+            service_name: "web".to_string(),
+            builder: builder(),
+            connector: Connector::new(None),
+            backends: Mutex::new(None),
+        };
+        let backends = || {
+            watch
+                .backends
+                .lock()
+                .unwrap()
+                .iter()
+                .flatten()
+                .map(|backend| backend.addr.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(watch.update(0).await.unwrap(), 5);
+        assert_eq!(backends(), vec!["10.0.0.1:8080"]);
+
+        // The index going backwards restarts from scratch
+        assert_eq!(watch.update(5).await.unwrap(), 0);
+        assert_eq!(backends(), vec!["10.0.0.2:8080"]);
+
+        // The last backends are kept on failure
+        assert!(watch.update(0).await.is_err());
+        assert_eq!(backends(), vec!["10.0.0.2:8080"]);
+
+        assert_eq!(
+            *paths.lock().unwrap(),
+            vec![
+                "/v1/health/service/web?passing=true",
+                "/v1/health/service/web?passing=true&index=5&wait=60s",
+                "/v1/health/service/web?passing=true",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_consul_discovery_drop() {
+        let (server, paths) =
+            mock_server(vec![response("200 OK", "X-Consul-Index: 5\r\n", "[]")]).await;
+        let conf: config::ConsulDiscovery =
+            serde_yaml::from_str(&format!("servers: [\"{}\"]", server)).unwrap();
+        let upstream: config::Upstream = serde_yaml::from_str("nodes: {}").unwrap();
+        let discovery = ConsulDiscovery::new(&conf, "web".to_string(), &upstream, None);
+
+        assert!(discovery.discover().await.unwrap().0.is_empty());
+        wait_requests(&paths, 2).await;
+
+        // Dropping the discovery aborts the pending blocking query
+        let watch = Arc::downgrade(&discovery.watch);
+        drop(discovery);
+        for _ in 0..500 {
+            if watch.upgrade().is_none() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the blocking query outlived the discovery");
+    }
+
+    #[tokio::test]
+    async fn test_kubernetes_watch() {
+        let slice = |version: &str, addr: &str| {
+            format!(
+                r#"{{"metadata": {{"name": "web-abc", "resourceVersion": "{}"}}, "endpoints": [{{"addresses": ["{}"]}}], "ports": [{{"name": "http", "port": 8080}}]}}"#,
+                version, addr
+            )
+        };
+        let list = |version: &str, addr: &str| {
+            format!(
+                r#"{{"metadata": {{"resourceVersion": "{}"}}, "items": [{}]}}"#,
+                version,
+                slice(version, addr)
+            )
+        };
+        let events = format!(
+            "{{\"type\": \"MODIFIED\", \"object\": {}}}\n{{\"type\": \"BOOKMARK\", \"object\": {{\"metadata\": {{\"resourceVersion\": \"12\"}}}}}}\n",
+            slice("11", "10.1.0.2")
+        );
+        let gone =
+            r#"{"type": "ERROR", "object": {"kind": "Status", "code": 410}}"#.to_string() + "\n";
+        let (server, paths) = mock_server(vec![
+            response("200 OK", "", &list("10", "10.1.0.1")),
+            response("200 OK", "", &events),
+            response("200 OK", "", &gone),
+            response("200 OK", "", &list("20", "10.1.0.3")),
+            response("500 Internal Server Error", "", ""),
+            response("500 Internal Server Error", "", ""),
+        ])
+        .await;
+        let conf: config::KubernetesDiscovery = serde_yaml::from_str(&format!(
+            "{{server: \"{}\", token_file: /nonexistent, retry_delay: 0}}",
+            server
+        ))
+        .unwrap();
+        let watch = Arc::new(KubernetesWatch {
+            conf,
+            server,
+            peer_tls: None,
+            service: "default/web:http".parse().unwrap(),
+            builder: builder(),
+            connector: Connector::new(None),
+            discovered: Mutex::new(None),
+        });
+
+        let task = tokio::spawn(watch_kubernetes(watch.clone(), None, HashMap::new()));
+        wait_requests(&paths, 7).await;
+        task.abort();
+
+        let path = |query: &str| watch.path(query);
+        let watch_path = |version: &str| {
+            path(&format!(
+                "&watch=true&allowWatchBookmarks=true&resourceVersion={}&timeoutSeconds=300",
+                version
+            ))
+        };
+        // Bookmarks move the resource version forward and a 410 ERROR event
+        // lists the slices again, so does the failed watch
+        assert_eq!(
+            *paths.lock().unwrap(),
+            vec![
+                path(""),
+                watch_path("10"),
+                watch_path("12"),
+                path(""),
+                watch_path("20"),
+                path(""),
+                path(""),
+            ]
+        );
+
+        // The last backends are kept on failure
+        let (backends, _) = watch.discovered.lock().unwrap().clone().unwrap();
+        let backends: Vec<String> = backends.iter().map(|b| b.addr.to_string()).collect();
+        assert_eq!(backends, vec!["10.1.0.3:8080"]);
+    }
+
+    #[test]
+    fn test_kubernetes_endpoints() {
+        assert_eq!(
+            "default/web:http".parse::<KubernetesService>().unwrap(),
+            KubernetesService {
+                namespace: "default".to_string(),
+                name: "web".to_string(),
+                port: Some("http".to_string()),
+            }
+        );
+        assert_eq!(
+            "default/web".parse::<KubernetesService>().unwrap().port,
+            None
+        );
+        for invalid in ["web", "/web", "default/", "default/web:"] {
+            assert!(invalid.parse::<KubernetesService>().is_err());
+        }
+
+        let list: EndpointSliceList = serde_json::from_str(
+            r#"{
+                "metadata": {"resourceVersion": "10"},
+                "items": [{
+                    "metadata": {"name": "web-abc", "resourceVersion": "9"},
+                    "addressType": "IPv4",
+                    "endpoints": [
+                        {"addresses": ["10.1.0.1"], "conditions": {"ready": true}},
+                        {"addresses": ["10.1.0.2"], "conditions": {"ready": false}}
+                    ],
+                    "ports": [{"name": "http", "port": 8080}, {"name": "metrics", "port": 9090}]
+                }]
+            }"#,
+        )
+        .unwrap();
+        let mut slices = list
+            .items
+            .unwrap()
+            .into_iter()
+            .map(|slice| (slice.metadata.name.clone(), slice))
+            .collect();
+
+        let addr = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+        let endpoints = slice_endpoints(&slices, Some("http"));
+        assert_eq!(endpoints.get(&addr("10.1.0.1:8080")), Some(&true));
+        assert_eq!(endpoints.get(&addr("10.1.0.2:8080")), Some(&false));
+        assert_eq!(slice_endpoints(&slices, Some("9090")).len(), 2);
+        assert!(slice_endpoints(&slices, Some("grpc")).is_empty());
+
+        // Ready in any slice means ready
+        let version = apply_watch_event(
+            br#"{"type": "ADDED", "object": {"metadata": {"name": "web-def", "resourceVersion": "11"}, "endpoints": [{"addresses": ["10.1.0.2"]}], "ports": [{"name": "http", "port": 8080}]}}"#,
+            &mut slices,
+        )
+        .unwrap();
+        assert_eq!(version.as_deref(), Some("11"));
+        let endpoints = slice_endpoints(&slices, Some("http"));
+        assert_eq!(endpoints.get(&addr("10.1.0.2:8080")), Some(&true));
+
+        apply_watch_event(
+            br#"{"type": "DELETED", "object": {"metadata": {"name": "web-abc", "resourceVersion": "12"}}}"#,
+            &mut slices,
+        )
+        .unwrap();
+        assert_eq!(slice_endpoints(&slices, None).len(), 1);
+
+        assert!(apply_watch_event(
+            br#"{"type": "ERROR", "object": {"kind": "Status", "code": 410}}"#,
+            &mut slices
+        )
+        .is_err());
+    }
 
     #[test]
     fn test_parse_upstream_node() {
@@ -246,5 +1923,6 @@ mod tests {
         assert!(parse_host_and_port("").is_err());
         assert!(parse_host_and_port("invalid:port").is_err());
         assert!(parse_host_and_port("127.0.0.1:invalid").is_err());
+        assert!(parse_host_and_port("127.0.0.1:70000").is_err());
     }
 }
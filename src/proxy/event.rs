@@ -4,10 +4,14 @@ use etcd_client::{Event, GetResponse};
 
 use crate::config::{
     etcd::{json_to_resource, EtcdEventHandler},
-    GlobalRule, Route, Service, Upstream,
+    Consumer, GlobalRule, Route, Service, Upstream,
 };
 
 use super::{
+    consumer::{
+        check_unique_credentials, consumer_fetch, reload_credential_index, ProxyConsumer,
+        CONSUMER_MAP,
+    },
     global_rule::{global_rule_fetch, reload_global_plugin, ProxyGlobalRule, GLOBAL_RULE_MAP},
     route::{reload_global_match, route_fetch, ProxyRoute, ROUTE_MAP},
     service::{service_fetch, ProxyService, SERVICE_MAP},
@@ -92,16 +96,25 @@ impl ProxyEventHandler {
         let proxy_upstreams: Vec<Arc<ProxyUpstream>> = upstream
             .iter()
             .filter_map(|upstream| {
-                if let Some(proxy_upstream) = upstream_fetch(&upstream.id) {
+                let previous = upstream_fetch(&upstream.id);
+                if let Some(proxy_upstream) = previous.as_ref() {
                     if proxy_upstream.inner == *upstream {
-                        return Some(proxy_upstream);
+                        return previous;
                     }
                 }
 
                 log::info!("Configuring Upstream: {}", upstream.id);
                 ProxyUpstream::new_with_health_check(upstream.clone(), self.work_stealing)
+                    .inspect_err(|e| {
+                        log::error!("Failed to configure Upstream {}: {}", upstream.id, e)
+                    })
                     .ok()
-                    .map(Arc::new)
+                    .map(|proxy_upstream| {
+                        if let Some(previous) = previous.as_ref() {
+                            proxy_upstream.inherit_stats(previous);
+                        }
+                        Arc::new(proxy_upstream)
+                    })
             })
             .collect();
 
@@ -146,6 +159,7 @@ impl ProxyEventHandler {
             .collect();
 
         SERVICE_MAP.reload_resource(proxy_services);
+        reload_global_match();
     }
 
     fn handle_global_rules(&self, response: &GetResponse) {
@@ -189,6 +203,47 @@ impl ProxyEventHandler {
         reload_global_plugin();
     }
 
+    fn handle_consumers(&self, response: &GetResponse) {
+        let consumers: Vec<Consumer> = response
+            .kvs()
+            .iter()
+            .filter_map(|kv| match parse_key(kv.key()) {
+                Ok((id, key_type)) if key_type == "consumers" => {
+                    match json_to_resource::<Consumer>(kv.value()) {
+                        Ok(mut consumer) => {
+                            consumer.username = id;
+                            Some(consumer)
+                        }
+                        Err(e) => {
+                            log::error!("Failed to load etcd Consumer: {} {}", id, e);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            })
+            .collect();
+
+        let proxy_consumers: Vec<Arc<ProxyConsumer>> = consumers
+            .iter()
+            .filter_map(|consumer| {
+                if let Some(proxy_consumer) = consumer_fetch(&consumer.username) {
+                    if proxy_consumer.inner == *consumer {
+                        return Some(proxy_consumer);
+                    }
+                }
+
+                log::info!("Configuring Consumer: {}", consumer.username);
+                ProxyConsumer::new_with_plugins(consumer.clone())
+                    .ok()
+                    .map(Arc::new)
+            })
+            .collect();
+
+        CONSUMER_MAP.reload_resource(proxy_consumers);
+        reload_credential_index();
+    }
+
     // 通用的资源处理函数
     fn handle_resource<T, F>(&self, event: &Event, key_type: &str, handler: F)
     where
@@ -231,11 +286,15 @@ impl ProxyEventHandler {
 
     fn handle_upstream_event(&self, event: &Event) {
         self.handle_resource::<Upstream, _>(event, "upstreams", |handler, id, upstream| {
-            if let Ok(mut proxy_upstream) =
-                ProxyUpstream::new_with_health_check(upstream.clone(), handler.work_stealing)
-            {
-                proxy_upstream.set_id(id);
-                UPSTREAM_MAP.insert(Arc::new(proxy_upstream));
+            match ProxyUpstream::new_with_health_check(upstream.clone(), handler.work_stealing) {
+                Ok(mut proxy_upstream) => {
+                    if let Some(previous) = upstream_fetch(&id) {
+                        proxy_upstream.inherit_stats(&previous);
+                    }
+                    proxy_upstream.set_id(id);
+                    UPSTREAM_MAP.insert(Arc::new(proxy_upstream));
+                }
+                Err(e) => log::error!("Failed to configure Upstream {}: {}", id, e),
             }
         });
     }
@@ -247,6 +306,7 @@ impl ProxyEventHandler {
             {
                 proxy_service.set_id(id);
                 SERVICE_MAP.insert(Arc::new(proxy_service));
+                reload_global_match();
             }
         });
     }
@@ -260,6 +320,28 @@ impl ProxyEventHandler {
             }
         });
     }
+
+    fn handle_consumer_event(&self, event: &Event) {
+        self.handle_resource::<Consumer, _>(event, "consumers", |_handler, id, consumer| {
+            let mut consumer = consumer.clone();
+            consumer.username = id;
+
+            let consumers = CONSUMER_MAP.read().unwrap().clone();
+            let others = consumers
+                .values()
+                .map(|other| &other.inner)
+                .filter(|other| other.username != consumer.username);
+            if let Err(e) = check_unique_credentials(others.chain([&consumer])) {
+                log::error!("Failed to configure Consumer {}: {}", consumer.username, e);
+                return;
+            }
+
+            if let Ok(proxy_consumer) = ProxyConsumer::new_with_plugins(consumer) {
+                CONSUMER_MAP.insert(Arc::new(proxy_consumer));
+                reload_credential_index();
+            }
+        });
+    }
 }
 
 impl EtcdEventHandler for ProxyEventHandler {
@@ -285,6 +367,9 @@ impl EtcdEventHandler for ProxyEventHandler {
                     "global_rules" => {
                         self.handle_global_rule_event(event);
                     }
+                    "consumers" => {
+                        self.handle_consumer_event(event);
+                    }
                     _ => {
                         log::warn!("Unhandled PUT event for key type: {}", key_type);
                     }
@@ -314,6 +399,7 @@ impl EtcdEventHandler for ProxyEventHandler {
                                 log::info!("DELETE Service: {}", id);
                                 // Handle the removal of a service
                                 SERVICE_MAP.remove(&id);
+                                reload_global_match();
                             }
                             "global_rules" => {
                                 log::info!("DELETE Global Rule: {}", id);
@@ -321,6 +407,12 @@ impl EtcdEventHandler for ProxyEventHandler {
                                 GLOBAL_RULE_MAP.remove(&id);
                                 reload_global_plugin();
                             }
+                            "consumers" => {
+                                log::info!("DELETE Consumer: {}", id);
+                                // Handle the removal of a consumer
+                                CONSUMER_MAP.remove(&id);
+                                reload_credential_index();
+                            }
                             _ => {
                                 log::warn!("Unhandled DELETE event for key type: {}", key_type);
                             }
@@ -339,6 +431,7 @@ impl EtcdEventHandler for ProxyEventHandler {
         self.handle_services(response);
         self.handle_routes(response);
         self.handle_global_rules(response);
+        self.handle_consumers(response);
     }
 }
 
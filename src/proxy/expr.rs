@@ -0,0 +1,215 @@
+use std::cmp::Ordering;
+
+use pingora_error::{Error, ErrorType::ReadError, OkOrErr, OrErr, Result};
+use regex::Regex;
+use serde_yaml::Value as YamlValue;
+
+/// A compiled APISIX-style `vars` expression.
+///
+/// Expressions are written as `[var, operator, value]`, optionally negated with
+/// `[var, "!", operator, value]`, e.g. `["http_x_canary", "==", "1"]`.
+pub struct VarExpr {
+    var: String,
+    negate: bool,
+    condition: Condition,
+}
+
+/// The comparison performed against the variable value.
+enum Condition {
+    Eq(String),
+    Ne(String),
+    Gt(f64),
+    Ge(f64),
+    Lt(f64),
+    Le(f64),
+    Regex(Regex),
+    In(Vec<String>),
+    Has(String),
+}
+
+impl VarExpr {
+    /// Returns the name of the variable this expression reads.
+    pub fn var(&self) -> &str {
+        self.var.as_str()
+    }
+
+    /// Evaluates the expression against the value of its variable.
+    ///
+    /// Missing variables are represented by an empty string.
+    pub fn eval(&self, value: &str) -> bool {
+        let matched = match &self.condition {
+            Condition::Eq(expected) => is_equal(value, expected),
+            Condition::Ne(expected) => !is_equal(value, expected),
+            Condition::Gt(expected) => numeric_cmp(value, *expected) == Some(Ordering::Greater),
+            Condition::Ge(expected) => matches!(
+                numeric_cmp(value, *expected),
+                Some(Ordering::Greater | Ordering::Equal)
+            ),
+            Condition::Lt(expected) => numeric_cmp(value, *expected) == Some(Ordering::Less),
+            Condition::Le(expected) => matches!(
+                numeric_cmp(value, *expected),
+                Some(Ordering::Less | Ordering::Equal)
+            ),
+            Condition::Regex(re) => re.is_match(value),
+            Condition::In(items) => items.iter().any(|item| item == value),
+            Condition::Has(item) => value.split(',').any(|v| v.trim() == item),
+        };
+
+        matched != self.negate
+    }
+}
+
+impl TryFrom<&[YamlValue]> for VarExpr {
+    type Error = Box<Error>;
+
+    fn try_from(value: &[YamlValue]) -> Result<Self> {
+        let (var, negate, op, operand) = match value {
+            [var, op, operand] => (var, false, op, operand),
+            [var, not, op, operand] if not.as_str() == Some("!") => (var, true, op, operand),
+            _ => return Error::e_explain(
+                ReadError,
+                "vars expression must be [var, operator, value] or [var, \"!\", operator, value]",
+            ),
+        };
+
+        let var = var
+            .as_str()
+            .or_err(ReadError, "vars expression variable must be a string")?
+            .to_string();
+
+        let condition = match op.as_str().unwrap_or_default() {
+            "==" => Condition::Eq(yaml_to_string(operand)?),
+            "~=" => Condition::Ne(yaml_to_string(operand)?),
+            ">" => Condition::Gt(yaml_to_number(operand)?),
+            ">=" => Condition::Ge(yaml_to_number(operand)?),
+            "<" => Condition::Lt(yaml_to_number(operand)?),
+            "<=" => Condition::Le(yaml_to_number(operand)?),
+            "~~" => Condition::Regex(
+                Regex::new(&yaml_to_string(operand)?)
+                    .or_err(ReadError, "Invalid regex in vars expression")?,
+            ),
+            "~*" => Condition::Regex(
+                Regex::new(&format!("(?i){}", yaml_to_string(operand)?))
+                    .or_err(ReadError, "Invalid regex in vars expression")?,
+            ),
+            "in" => match operand {
+                YamlValue::Sequence(items) => Condition::In(
+                    items
+                        .iter()
+                        .map(yaml_to_string)
+                        .collect::<Result<Vec<_>>>()?,
+                ),
+                _ => return Error::e_explain(ReadError, "vars operator `in` requires an array"),
+            },
+            "has" => Condition::Has(yaml_to_string(operand)?),
+            op => return Error::e_explain(ReadError, format!("Unsupported vars operator: {}", op)),
+        };
+
+        Ok(Self {
+            var,
+            negate,
+            condition,
+        })
+    }
+}
+
+/// Compiles a list of vars expressions.
+pub fn parse_vars(vars: &[Vec<YamlValue>]) -> Result<Vec<VarExpr>> {
+    vars.iter()
+        .map(|expr| VarExpr::try_from(expr.as_slice()))
+        .collect()
+}
+
+/// Evaluates vars expressions, all of them must match.
+pub fn eval_vars<F>(exprs: &[VarExpr], lookup: F) -> bool
+where
+    F: Fn(&str) -> String,
+{
+    exprs.iter().all(|expr| expr.eval(&lookup(expr.var())))
+}
+
+fn yaml_to_string(value: &YamlValue) -> Result<String> {
+    match value {
+        YamlValue::String(s) => Ok(s.clone()),
+        YamlValue::Number(n) => Ok(n.to_string()),
+        YamlValue::Bool(b) => Ok(b.to_string()),
+        _ => Error::e_explain(ReadError, "vars expression value must be a scalar"),
+    }
+}
+
+fn yaml_to_number(value: &YamlValue) -> Result<f64> {
+    yaml_to_string(value)?
+        .trim()
+        .parse::<f64>()
+        .or_err(ReadError, "vars expression value must be a number")
+}
+
+fn is_equal(value: &str, expected: &str) -> bool {
+    value == expected
+        || matches!(
+            (value.trim().parse::<f64>(), expected.parse::<f64>()),
+            (Ok(a), Ok(b)) if a == b
+        )
+}
+
+fn numeric_cmp(value: &str, expected: f64) -> Option<Ordering> {
+    value.trim().parse::<f64>().ok()?.partial_cmp(&expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn eval(vars: &str, values: &[(&str, &str)]) -> bool {
+        let vars: Vec<Vec<YamlValue>> = serde_yaml::from_str(vars).unwrap();
+        let exprs = parse_vars(&vars).unwrap();
+        let values: HashMap<&str, &str> = values.iter().cloned().collect();
+        eval_vars(&exprs, |key| {
+            values.get(key).map(|v| v.to_string()).unwrap_or_default()
+        })
+    }
+
+    #[test]
+    fn test_eval_vars_operators() {
+        let values = [
+            ("http_x_canary", "1"),
+            ("arg_version", "3"),
+            ("uri", "/api/v1"),
+        ];
+
+        assert!(eval(r#"[["http_x_canary", "==", "1"]]"#, &values));
+        assert!(eval(r#"[["http_x_canary", "==", 1]]"#, &values));
+        assert!(eval(r#"[["http_x_canary", "~=", "2"]]"#, &values));
+        assert!(eval(
+            r#"[["arg_version", ">=", 2], ["arg_version", "<", 4]]"#,
+            &values
+        ));
+        assert!(!eval(r#"[["arg_version", ">", 3]]"#, &values));
+        assert!(eval(r#"[["uri", "~~", "^/api/v[0-9]+$"]]"#, &values));
+        assert!(eval(r#"[["arg_version", "in", ["1", "3"]]]"#, &values));
+        assert!(eval(r#"[["http_x_canary", "!", "==", "0"]]"#, &values));
+        assert!(!eval(r#"[["http_x_missing", "==", "1"]]"#, &values));
+        assert!(eval(
+            r#"[["http_x_tags", "has", "b"]]"#,
+            &[("http_x_tags", "a, b")]
+        ));
+    }
+
+    #[test]
+    fn test_parse_vars_invalid() {
+        let invalid = [
+            r#"[["arg_name"]]"#,
+            r#"[["arg_name", "<>", "1"]]"#,
+            r#"[["arg_name", ">", "abc"]]"#,
+            r#"[["arg_name", "~~", "("]]"#,
+            r#"[["arg_name", "in", "1"]]"#,
+        ];
+
+        for vars in invalid {
+            let vars: Vec<Vec<YamlValue>> = serde_yaml::from_str(vars).unwrap();
+            assert!(parse_vars(&vars).is_err());
+        }
+    }
+}
@@ -1,5 +1,7 @@
+pub mod consumer;
 pub mod discovery;
 pub mod event;
+pub mod expr;
 pub mod global_rule;
 pub mod plugin;
 pub mod route;
@@ -12,11 +14,15 @@ use std::{
     time::Instant,
 };
 
+use bytes::BytesMut;
+use pingora_core::{protocols::l4::socket::SocketAddr, upstreams::peer::HttpPeer};
 use pingora_http::RequestHeader;
 use pingora_proxy::Session;
 
+use consumer::ProxyConsumer;
 use plugin::ProxyPluginExecutor;
 use route::ProxyRoute;
+use upstream::{ConnectionGuard, InflightGuard};
 
 use crate::config;
 
@@ -30,6 +36,16 @@ pub struct ProxyContext {
     pub request_start: Instant,
     pub plugin: Arc<ProxyPluginExecutor>,
     pub vars: HashMap<String, String>,
+    /// The in-flight request to the selected backend
+    pub inflight: Option<InflightGuard>,
+    /// Backends already tried by previous attempts of this request
+    pub tried_backends: Vec<SocketAddr>,
+    /// The connection to the upstream used by the current attempt
+    pub connection: Option<ConnectionGuard>,
+    /// The consumer authenticated by an auth plugin
+    pub consumer: Option<Arc<ProxyConsumer>>,
+    /// The request body held back by plugins checking it as a whole
+    pub request_body: BytesMut,
 }
 
 impl Default for ProxyContext {
@@ -41,10 +57,26 @@ impl Default for ProxyContext {
             request_start: Instant::now(),
             plugin: Arc::new(ProxyPluginExecutor::default()),
             vars: HashMap::new(),
+            inflight: None,
+            tried_backends: Vec::new(),
+            connection: None,
+            consumer: None,
+            request_body: BytesMut::new(),
         }
     }
 }
 
+/// Applies configured timeouts to a peer.
+///
+/// Route timeouts are applied after the upstream ones and replace them as a
+/// whole, a route timeout without `total` also clears the one of the upstream.
+pub fn apply_timeout(timeout: &config::Timeout, p: &mut HttpPeer) {
+    p.options.connection_timeout = Some(timeout.connect);
+    p.options.read_timeout = Some(timeout.read);
+    p.options.write_timeout = Some(timeout.send);
+    p.options.total_connection_timeout = timeout.total;
+}
+
 /// Build request selector key.
 pub fn request_selector_key(
     session: &mut Session,
@@ -63,7 +95,7 @@ pub fn request_selector_key(
 }
 
 /// Handles variable-based request selection.
-fn handle_vars(session: &mut Session, key: &str) -> String {
+fn handle_vars(session: &Session, key: &str) -> String {
     if key.starts_with("arg_") {
         if let Some(name) = key.strip_prefix("arg_") {
             return get_query_value(session.req_header(), name)
@@ -72,8 +104,24 @@ fn handle_vars(session: &mut Session, key: &str) -> String {
         }
     }
 
+    if let Some(name) = key.strip_prefix("http_") {
+        return get_req_header_value(session.req_header(), &name.replace('_', "-"))
+            .unwrap_or_default()
+            .to_string();
+    }
+
+    if let Some(name) = key.strip_prefix("cookie_") {
+        return get_cookie_value(session.req_header(), name)
+            .unwrap_or_default()
+            .to_string();
+    }
+
     match key {
         "uri" => session.req_header().uri.path().to_string(),
+        "host" => get_request_host(session.req_header())
+            .unwrap_or_default()
+            .to_string(),
+        "request_method" => session.req_header().method.to_string(),
         "request_uri" => session
             .req_header()
             .uri
@@ -99,7 +147,7 @@ fn handle_vars(session: &mut Session, key: &str) -> String {
     }
 }
 
-fn get_query_value<'a>(req_header: &'a RequestHeader, name: &str) -> Option<&'a str> {
+pub fn get_query_value<'a>(req_header: &'a RequestHeader, name: &str) -> Option<&'a str> {
     if let Some(query) = req_header.uri.query() {
         for item in query.split('&') {
             if let Some((k, v)) = item.split_once('=') {
@@ -112,6 +160,28 @@ fn get_query_value<'a>(req_header: &'a RequestHeader, name: &str) -> Option<&'a
     None
 }
 
+/// Removes a query argument from an URI, returns `None` when it is absent.
+pub fn remove_query_arg(uri: &http::Uri, name: &str) -> Option<http::Uri> {
+    let query = uri.query()?;
+    let is_arg = |item: &&str| item.split('=').next() == Some(name);
+    if !query.split('&').any(|item| is_arg(&item)) {
+        return None;
+    }
+
+    let query = query
+        .split('&')
+        .filter(|item| !is_arg(item))
+        .collect::<Vec<_>>()
+        .join("&");
+    let path_and_query = if query.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), query)
+    };
+
+    path_and_query.parse().ok()
+}
+
 fn get_req_header_value<'a>(req_header: &'a RequestHeader, key: &str) -> Option<&'a str> {
     if let Some(value) = req_header.headers.get(key) {
         if let Ok(value) = value.to_str() {
@@ -121,19 +191,22 @@ fn get_req_header_value<'a>(req_header: &'a RequestHeader, key: &str) -> Option<
     None
 }
 
-fn get_cookie_value<'a>(req_header: &'a RequestHeader, cookie_name: &str) -> Option<&'a str> {
-    if let Some(cookie_value) = get_req_header_value(req_header, "Cookie") {
-        for item in cookie_value.split(';') {
-            if let Some((k, v)) = item.split_once('=') {
-                if k == cookie_name {
-                    return Some(v.trim());
-                }
-            }
-        }
+pub fn get_cookie_value<'a>(req_header: &'a RequestHeader, cookie_name: &str) -> Option<&'a str> {
+    let value = find_cookie_value(req_header, cookie_name);
+    if value.is_none() {
+        log::warn!("Cookie '{}' not found or malformed.", cookie_name);
     }
+    value
+}
 
-    log::warn!("Cookie '{}' not found or malformed.", cookie_name);
-    None
+/// Gets the value of a cookie, without warning when it is absent as optional
+/// cookies are expected to be.
+pub fn find_cookie_value<'a>(req_header: &'a RequestHeader, cookie_name: &str) -> Option<&'a str> {
+    let cookie_value = get_req_header_value(req_header, "Cookie")?;
+    cookie_value.split(';').find_map(|item| {
+        let (k, v) = item.split_once('=')?;
+        (k.trim() == cookie_name).then(|| v.trim())
+    })
 }
 
 /// Retrieves the request host from the request header.
@@ -204,3 +277,39 @@ where
         map.get(id).cloned()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_apply_timeout() {
+        let upstream: config::Timeout =
+            serde_yaml::from_str("{connect: 1, send: 2, read: 3, total: 4}").unwrap();
+        let route: config::Timeout =
+            serde_yaml::from_str("{connect: 0.5, send: 0.25, read: 1.5}").unwrap();
+        let mut peer = HttpPeer::new("127.0.0.1:80", false, String::new());
+
+        apply_timeout(&upstream, &mut peer);
+        assert_eq!(
+            peer.options.connection_timeout,
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            peer.options.total_connection_timeout,
+            Some(Duration::from_secs(4))
+        );
+
+        // The route timeout replaces the upstream one as a whole
+        apply_timeout(&route, &mut peer);
+        assert_eq!(
+            peer.options.connection_timeout,
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(peer.options.write_timeout, Some(Duration::from_millis(250)));
+        assert_eq!(peer.options.read_timeout, Some(Duration::from_millis(1500)));
+        assert_eq!(peer.options.total_connection_timeout, None);
+    }
+}
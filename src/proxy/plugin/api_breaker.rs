@@ -0,0 +1,354 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use http::header;
+use pingora_error::{ErrorType::ReadError, OrErr, Result};
+use pingora_http::ResponseHeader;
+use pingora_proxy::Session;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value as YamlValue;
+use validator::Validate;
+
+use crate::proxy::ProxyContext;
+
+use super::ProxyPlugin;
+
+pub const PLUGIN_NAME: &str = "api-breaker";
+
+/// Creates an API Breaker plugin instance.
+pub fn create_api_breaker_plugin(cfg: YamlValue) -> Result<Arc<dyn ProxyPlugin>> {
+    let config: PluginConfig = serde_yaml::from_value(cfg)
+        .or_err_with(ReadError, || "Invalid api breaker plugin config")?;
+
+    config
+        .validate()
+        .or_err_with(ReadError, || "Invalid api breaker plugin config")?;
+
+    Ok(Arc::new(PluginApiBreaker {
+        config,
+        states: Mutex::new(HashMap::new()),
+    }))
+}
+
+/// Configuration for the API Breaker plugin.
+#[derive(Debug, Serialize, Deserialize, Validate)]
+struct PluginConfig {
+    /// Status code returned while the circuit is open.
+    #[validate(range(min = 200, max = 599))]
+    break_response_code: u16,
+    break_response_body: Option<String>,
+    #[serde(default)]
+    break_response_headers: Vec<ResponseHeaderItem>,
+
+    /// Upper bound of the break duration in seconds.
+    #[serde(default = "PluginConfig::default_max_breaker_sec")]
+    #[validate(range(min = 3))]
+    max_breaker_sec: u64,
+
+    #[serde(default)]
+    #[validate(nested)]
+    unhealthy: Unhealthy,
+    #[serde(default)]
+    #[validate(nested)]
+    healthy: Healthy,
+}
+
+impl PluginConfig {
+    fn default_max_breaker_sec() -> u64 {
+        300
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ResponseHeaderItem {
+    key: String,
+    value: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+struct Unhealthy {
+    #[serde(default = "Unhealthy::default_http_statuses")]
+    http_statuses: Vec<u16>,
+    /// Consecutive unhealthy responses that open the circuit.
+    #[serde(default = "Unhealthy::default_failures")]
+    #[validate(range(min = 1))]
+    failures: u32,
+}
+
+impl Default for Unhealthy {
+    fn default() -> Self {
+        Self {
+            http_statuses: Self::default_http_statuses(),
+            failures: Self::default_failures(),
+        }
+    }
+}
+
+impl Unhealthy {
+    fn default_http_statuses() -> Vec<u16> {
+        vec![500]
+    }
+
+    fn default_failures() -> u32 {
+        3
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+struct Healthy {
+    #[serde(default = "Healthy::default_http_statuses")]
+    http_statuses: Vec<u16>,
+    /// Consecutive healthy responses that close the circuit.
+    #[serde(default = "Healthy::default_successes")]
+    #[validate(range(min = 1))]
+    successes: u32,
+}
+
+impl Default for Healthy {
+    fn default() -> Self {
+        Self {
+            http_statuses: Self::default_http_statuses(),
+            successes: Self::default_successes(),
+        }
+    }
+}
+
+impl Healthy {
+    fn default_http_statuses() -> Vec<u16> {
+        vec![200]
+    }
+
+    fn default_successes() -> u32 {
+        3
+    }
+}
+
+/// Circuit state of a single route.
+#[derive(Default)]
+struct BreakerState {
+    /// Consecutive unhealthy responses since the circuit last opened
+    unhealthy: u32,
+    /// Consecutive healthy responses after the circuit opened
+    healthy: u32,
+    /// Times the circuit opened since it was last closed by healthy responses
+    opened: u32,
+    /// The circuit is open until this instant
+    open_until: Option<Instant>,
+}
+
+impl BreakerState {
+    /// Counts an unhealthy response, opens the circuit after `failures` consecutive
+    /// ones and returns the break duration in seconds.
+    fn on_unhealthy(&mut self, config: &PluginConfig, now: Instant) -> Option<u64> {
+        self.healthy = 0;
+        self.unhealthy += 1;
+        if self.unhealthy < config.unhealthy.failures {
+            return None;
+        }
+
+        self.unhealthy = 0;
+        self.opened += 1;
+        let secs = 2u64.pow(self.opened.min(32)).min(config.max_breaker_sec);
+        self.open_until = Some(now + Duration::from_secs(secs));
+        Some(secs)
+    }
+
+    /// Counts a healthy response, returns true once the route is healthy again and
+    /// its state can be forgotten.
+    fn on_healthy(&mut self, config: &PluginConfig) -> bool {
+        self.unhealthy = 0;
+        if self.opened == 0 {
+            return true;
+        }
+
+        self.healthy += 1;
+        self.healthy >= config.healthy.successes
+    }
+}
+
+/// API Breaker plugin implementation.
+///
+/// Opens the circuit of a route after consecutive unhealthy upstream responses,
+/// the break duration doubles each time the circuit opens again, up to
+/// `max_breaker_sec`.
+pub struct PluginApiBreaker {
+    config: PluginConfig,
+    /// Circuit states keyed by route id
+    states: Mutex<HashMap<String, BreakerState>>,
+}
+
+#[async_trait]
+impl ProxyPlugin for PluginApiBreaker {
+    fn name(&self) -> &str {
+        PLUGIN_NAME
+    }
+
+    fn priority(&self) -> i32 {
+        1005
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut ProxyContext) -> Result<bool> {
+        let is_open = self
+            .states
+            .lock()
+            .unwrap()
+            .get(&route_key(ctx))
+            .and_then(|state| state.open_until)
+            .is_some_and(|until| Instant::now() < until);
+
+        if is_open {
+            return self.break_response(session).await;
+        }
+
+        Ok(false)
+    }
+
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut ProxyContext,
+    ) -> Result<()> {
+        let status = upstream_response.status.as_u16();
+        let mut states = self.states.lock().unwrap();
+
+        if self.config.unhealthy.http_statuses.contains(&status) {
+            let state = states.entry(route_key(ctx)).or_default();
+            if let Some(secs) = state.on_unhealthy(&self.config, Instant::now()) {
+                log::warn!("Circuit of route {} opened for {}s", route_key(ctx), secs);
+            }
+        } else if self.config.healthy.http_statuses.contains(&status) {
+            let key = route_key(ctx);
+            if states
+                .get_mut(&key)
+                .is_some_and(|state| state.on_healthy(&self.config))
+            {
+                states.remove(&key);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PluginApiBreaker {
+    /// Responds with the configured break response
+    async fn break_response(&self, session: &mut Session) -> Result<bool> {
+        let mut header = ResponseHeader::build(self.config.break_response_code, None)?;
+
+        for item in &self.config.break_response_headers {
+            header.insert_header(item.key.clone(), item.value.clone())?;
+        }
+
+        if let Some(ref body) = self.config.break_response_body {
+            header.insert_header(header::CONTENT_LENGTH, body.len().to_string())?;
+            session
+                .write_response_header(Box::new(header), false)
+                .await?;
+            session
+                .write_response_body(Some(body.clone().into()), true)
+                .await?;
+        } else {
+            header.insert_header(header::CONTENT_LENGTH, "0")?;
+            session
+                .write_response_header(Box::new(header), true)
+                .await?;
+        }
+
+        Ok(true)
+    }
+}
+
+fn route_key(ctx: &ProxyContext) -> String {
+    ctx.route
+        .as_ref()
+        .map(|route| route.inner.id.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(cfg: &str) -> PluginConfig {
+        serde_yaml::from_str(cfg).unwrap()
+    }
+
+    #[test]
+    fn test_breaker_opens_with_backoff() {
+        let config =
+            config("{break_response_code: 502, max_breaker_sec: 10, unhealthy: {failures: 2}}");
+        let now = Instant::now();
+        let mut state = BreakerState::default();
+
+        // Every `failures` consecutive unhealthy responses double the break, up
+        // to max_breaker_sec
+        let mut opened = Vec::new();
+        for _ in 0..10 {
+            opened.push(state.on_unhealthy(&config, now));
+        }
+        assert_eq!(
+            opened,
+            vec![
+                None,
+                Some(2),
+                None,
+                Some(4),
+                None,
+                Some(8),
+                None,
+                Some(10),
+                None,
+                Some(10)
+            ]
+        );
+        assert_eq!(state.open_until, Some(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_breaker_closes_after_successes() {
+        let config = config("{break_response_code: 502, unhealthy: {failures: 1}}");
+        let now = Instant::now();
+        let mut state = BreakerState::default();
+
+        assert_eq!(state.on_unhealthy(&config, now), Some(2));
+        assert!(!state.on_healthy(&config));
+        assert!(!state.on_healthy(&config));
+
+        // An unhealthy response starts the count of successes over
+        assert_eq!(state.on_unhealthy(&config, now), Some(4));
+        assert!(!state.on_healthy(&config));
+        assert!(!state.on_healthy(&config));
+        assert!(state.on_healthy(&config));
+    }
+
+    #[test]
+    fn test_breaker_healthy_resets_unhealthy_count() {
+        let config = config("{break_response_code: 502, unhealthy: {failures: 3}}");
+        let now = Instant::now();
+        let mut state = BreakerState::default();
+
+        assert_eq!(state.on_unhealthy(&config, now), None);
+        assert_eq!(state.on_unhealthy(&config, now), None);
+        // Failures must be consecutive, the closed circuit is healthy again
+        assert!(state.on_healthy(&config));
+
+        let mut state = BreakerState::default();
+        assert_eq!(state.on_unhealthy(&config, now), None);
+        assert_eq!(state.on_unhealthy(&config, now), None);
+        assert_eq!(state.on_unhealthy(&config, now), Some(2));
+
+        // After the break, a healthy response in between resets the unhealthy
+        // count but keeps the backoff
+        assert_eq!(state.on_unhealthy(&config, now), None);
+        assert!(!state.on_healthy(&config));
+        assert_eq!(state.on_unhealthy(&config, now), None);
+        assert_eq!(state.on_unhealthy(&config, now), None);
+        assert_eq!(state.on_unhealthy(&config, now), Some(4));
+        assert!(state.open_until.is_some());
+    }
+}
@@ -0,0 +1,259 @@
+use std::{str::FromStr, sync::Arc};
+
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::header;
+use pingora_error::{ErrorType::ReadError, OrErr, Result};
+use pingora_http::RequestHeader;
+use pingora_proxy::Session;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value as YamlValue;
+use validator::{Validate, ValidationError};
+
+use crate::proxy::{consumer::consumer_fetch_by_credential, ProxyContext};
+
+use super::{attach_consumer, constant_time_eq, unauthorized, ProxyPlugin};
+
+pub const PLUGIN_NAME: &str = "basic-auth";
+
+const CHALLENGE: &str = "Basic realm=\"pingsix\"";
+
+/// Creates a Basic Auth plugin instance.
+pub fn create_basic_auth_plugin(cfg: YamlValue) -> Result<Arc<dyn ProxyPlugin>> {
+    let config: PluginConfig = serde_yaml::from_value(cfg)
+        .or_err_with(ReadError, || "Invalid basic auth plugin config")?;
+
+    config
+        .validate()
+        .or_err_with(ReadError, || "Invalid basic auth plugin config")?;
+
+    Ok(Arc::new(PluginBasicAuth { config }))
+}
+
+/// Configuration for the Basic Auth plugin.
+///
+/// On routes it only tells whether the credentials reach the upstream, on
+/// consumers it holds the user and its password.
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[validate(schema(function = "PluginConfig::validate_credentials"))]
+struct PluginConfig {
+    /// Removes the `Authorization` header from the request sent to the upstream.
+    #[serde(default)]
+    hide_credentials: bool,
+
+    /// User of a consumer.
+    #[validate(length(min = 1))]
+    username: Option<String>,
+    /// Password of a consumer, either plaintext or a bcrypt/argon2 hash.
+    #[validate(length(min = 1))]
+    password: Option<String>,
+}
+
+impl PluginConfig {
+    fn validate_credentials(&self) -> Result<(), ValidationError> {
+        if self.username.is_some() != self.password.is_some() {
+            return Err(ValidationError::new("username_and_password_required"));
+        }
+
+        if let Some(password) = &self.password {
+            if is_bcrypt_hash(password) && bcrypt::HashParts::from_str(password).is_err() {
+                return Err(ValidationError::new("invalid_bcrypt_hash"));
+            }
+            if password.starts_with("$argon2") && PasswordHash::new(password).is_err() {
+                return Err(ValidationError::new("invalid_argon2_hash"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Basic Auth plugin implementation.
+///
+/// Authenticates the consumer owning the user of the `Authorization: Basic` header,
+/// users are unique among consumers.
+pub struct PluginBasicAuth {
+    config: PluginConfig,
+}
+
+#[async_trait]
+impl ProxyPlugin for PluginBasicAuth {
+    fn name(&self) -> &str {
+        PLUGIN_NAME
+    }
+
+    fn priority(&self) -> i32 {
+        2520
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut ProxyContext) -> Result<bool> {
+        let Some(authorization) = session
+            .get_header(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return unauthorized(session, "Missing authorization in request", Some(CHALLENGE))
+                .await;
+        };
+
+        let Some((username, password)) = parse_basic_auth(authorization) else {
+            return unauthorized(session, "Invalid authorization in request", Some(CHALLENGE))
+                .await;
+        };
+
+        let consumer = consumer_fetch_by_credential(PLUGIN_NAME, &username).filter(|consumer| {
+            consumer
+                .credential(PLUGIN_NAME, "password")
+                .is_some_and(|expected| verify_password(&password, expected))
+        });
+
+        match consumer {
+            Some(consumer) => {
+                attach_consumer(ctx, consumer);
+                Ok(false)
+            }
+            None => unauthorized(session, "Invalid user authorization", Some(CHALLENGE)).await,
+        }
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        _ctx: &mut ProxyContext,
+    ) -> Result<()> {
+        if self.config.hide_credentials {
+            upstream_request.remove_header(&header::AUTHORIZATION);
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses the user and password of an `Authorization: Basic` header value.
+fn parse_basic_auth(value: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = STANDARD.decode(credentials.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    if username.is_empty() {
+        return None;
+    }
+
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Verifies a password against the configured one, hashed passwords are
+/// recognized by their bcrypt (`$2a$`, `$2b$`, `$2y$`) or argon2 (`$argon2`) prefix.
+fn verify_password(password: &str, expected: &str) -> bool {
+    if is_bcrypt_hash(expected) {
+        return bcrypt::verify(password, expected).unwrap_or(false);
+    }
+
+    if expected.starts_with("$argon2") {
+        return PasswordHash::new(expected).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        });
+    }
+
+    constant_time_eq(password.as_bytes(), expected.as_bytes())
+}
+
+fn is_bcrypt_hash(password: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$"]
+        .iter()
+        .any(|prefix| password.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::{
+        password_hash::{PasswordHasher, SaltString},
+        Argon2,
+    };
+
+    use super::*;
+
+    fn config(cfg: &str) -> PluginConfig {
+        serde_yaml::from_str(cfg).unwrap()
+    }
+
+    #[test]
+    fn test_parse_basic_auth() {
+        let encoded = STANDARD.encode("jack:pa:ss");
+        assert_eq!(
+            parse_basic_auth(&format!("Basic {}", encoded)),
+            Some(("jack".to_string(), "pa:ss".to_string()))
+        );
+        assert_eq!(
+            parse_basic_auth(&format!("  basic   {} ", encoded)),
+            Some(("jack".to_string(), "pa:ss".to_string()))
+        );
+        assert_eq!(
+            parse_basic_auth(&format!("Basic {}", STANDARD.encode("jack:"))),
+            Some(("jack".to_string(), String::new()))
+        );
+
+        assert_eq!(parse_basic_auth(&format!("Bearer {}", encoded)), None);
+        assert_eq!(parse_basic_auth("Basic not-base64!"), None);
+        assert_eq!(
+            parse_basic_auth(&format!("Basic {}", STANDARD.encode("jack"))),
+            None
+        );
+        assert_eq!(
+            parse_basic_auth(&format!("Basic {}", STANDARD.encode(":pass"))),
+            None
+        );
+        assert_eq!(parse_basic_auth("Basic"), None);
+    }
+
+    #[test]
+    fn test_verify_password() {
+        assert!(verify_password("secret", "secret"));
+        assert!(!verify_password("secret", "secret!"));
+        assert!(!verify_password("", "secret"));
+
+        let bcrypt_hash = bcrypt::hash("secret", 4).unwrap();
+        assert!(verify_password("secret", &bcrypt_hash));
+        assert!(!verify_password("other", &bcrypt_hash));
+
+        let salt = SaltString::encode_b64(b"pingsix-test-salt").unwrap();
+        let argon2_hash = Argon2::default()
+            .hash_password(b"secret", &salt)
+            .unwrap()
+            .to_string();
+        assert!(verify_password("secret", &argon2_hash));
+        assert!(!verify_password("other", &argon2_hash));
+
+        // A hash is never compared as a plaintext password
+        assert!(!verify_password(&bcrypt_hash, &bcrypt_hash));
+        assert!(!verify_password(&argon2_hash, &argon2_hash));
+    }
+
+    #[test]
+    fn test_validate_password_hash() {
+        let bcrypt_hash = bcrypt::hash("secret", 4).unwrap();
+        assert!(
+            config(&format!("{{username: jack, password: '{}'}}", bcrypt_hash))
+                .validate()
+                .is_ok()
+        );
+        assert!(config("{username: jack, password: plain}")
+            .validate()
+            .is_ok());
+
+        assert!(config("{username: jack, password: '$2b$04$short'}")
+            .validate()
+            .is_err());
+        assert!(config("{username: jack, password: '$argon2id$$'}")
+            .validate()
+            .is_err());
+        assert!(config("username: jack").validate().is_err());
+    }
+}
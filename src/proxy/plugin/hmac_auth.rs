@@ -0,0 +1,721 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use http::header;
+use once_cell::sync::Lazy;
+use pingora_error::{Error, ErrorType, ErrorType::ReadError, OkOrErr, OrErr, Result};
+use pingora_proxy::Session;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value as YamlValue;
+use sha2::{Digest, Sha256, Sha512};
+use validator::{Validate, ValidationError};
+
+use crate::proxy::{
+    consumer::{consumer_fetch_by_credential, ProxyConsumer},
+    ProxyContext,
+};
+
+use super::{attach_consumer, unauthorized, ProxyPlugin};
+
+pub const PLUGIN_NAME: &str = "hmac-auth";
+
+const ACCESS_KEY_HEADER: &str = "x-hmac-access-key";
+const SIGNATURE_HEADER: &str = "x-hmac-signature";
+const ALGORITHM_HEADER: &str = "x-hmac-algorithm";
+const SIGNED_HEADERS_HEADER: &str = "x-hmac-signed-headers";
+
+/// Request variable holding the `Digest` header the body is checked against
+const DIGEST_VAR: &str = "hmac_auth_digest";
+
+/// Most signatures remembered for replay detection
+const MAX_SIGNATURES: usize = 100_000;
+
+/// Signatures already seen, shared by all routes so that a route reload keeps them.
+static SIGNATURES: Lazy<Mutex<ReplayCache>> =
+    Lazy::new(|| Mutex::new(ReplayCache::new(MAX_SIGNATURES)));
+
+/// Creates a HMAC Auth plugin instance.
+pub fn create_hmac_auth_plugin(cfg: YamlValue) -> Result<Arc<dyn ProxyPlugin>> {
+    let config: PluginConfig =
+        serde_yaml::from_value(cfg).or_err_with(ReadError, || "Invalid hmac auth plugin config")?;
+
+    config
+        .validate()
+        .or_err_with(ReadError, || "Invalid hmac auth plugin config")?;
+
+    Ok(Arc::new(PluginHmacAuth { config }))
+}
+
+/// Configuration for the HMAC Auth plugin.
+///
+/// On routes it tells how signatures are checked, on consumers it holds the
+/// access key and its secret.
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[validate(schema(function = "PluginConfig::validate_credentials"))]
+struct PluginConfig {
+    /// Accepted signing algorithms.
+    #[serde(default = "PluginConfig::default_allowed_algorithms")]
+    #[validate(length(min = 1))]
+    allowed_algorithms: Vec<HmacAlgorithm>,
+    /// Maximum difference in seconds between the `Date` header and the local clock,
+    /// signatures are also remembered this long to reject replays.
+    #[serde(default = "PluginConfig::default_clock_skew")]
+    #[validate(range(min = 1))]
+    clock_skew: u64,
+    /// Headers every request must sign, in addition to `Date`.
+    #[serde(default)]
+    signed_headers: Vec<String>,
+    /// Requires a signed `Digest` header and checks it against the request body.
+    ///
+    /// The body is checked while it streams to the upstream, which has already
+    /// received the request headers when a mismatch aborts the request.
+    #[serde(default)]
+    validate_request_body: bool,
+    /// Largest request body checked against its digest, in bytes.
+    #[serde(default = "PluginConfig::default_max_req_body")]
+    #[validate(range(min = 1))]
+    max_req_body: usize,
+
+    /// Access key of a consumer.
+    #[validate(length(min = 1))]
+    access_key: Option<String>,
+    /// Secret key of a consumer.
+    #[validate(length(min = 1))]
+    secret_key: Option<String>,
+}
+
+impl PluginConfig {
+    fn default_allowed_algorithms() -> Vec<HmacAlgorithm> {
+        vec![HmacAlgorithm::HmacSha256, HmacAlgorithm::HmacSha512]
+    }
+
+    fn default_clock_skew() -> u64 {
+        300
+    }
+
+    fn default_max_req_body() -> usize {
+        512 * 1024
+    }
+
+    fn validate_credentials(&self) -> Result<(), ValidationError> {
+        if self.access_key.is_some() != self.secret_key.is_some() {
+            return Err(ValidationError::new("access_key_and_secret_key_required"));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum HmacAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl HmacAlgorithm {
+    fn from_header(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "hmac-sha256" => Some(Self::HmacSha256),
+            "hmac-sha512" => Some(Self::HmacSha512),
+            _ => None,
+        }
+    }
+
+    /// Checks the signature of the signing string in constant time.
+    fn verify(&self, secret: &[u8], signing_string: &str, signature: &[u8]) -> bool {
+        match self {
+            Self::HmacSha256 => Hmac::<Sha256>::new_from_slice(secret).is_ok_and(|mut mac| {
+                mac.update(signing_string.as_bytes());
+                mac.verify_slice(signature).is_ok()
+            }),
+            Self::HmacSha512 => Hmac::<Sha512>::new_from_slice(secret).is_ok_and(|mut mac| {
+                mac.update(signing_string.as_bytes());
+                mac.verify_slice(signature).is_ok()
+            }),
+        }
+    }
+}
+
+/// HMAC Auth plugin implementation.
+///
+/// Authenticates the consumer owning the access key of a request signed with its
+/// secret key. The signing string is:
+///
+/// ```text
+/// METHOD\n
+/// path\n
+/// query arguments sorted by name, joined with &\n
+/// access key\n
+/// Date header\n
+/// name:value\n of each signed header
+/// ```
+pub struct PluginHmacAuth {
+    config: PluginConfig,
+}
+
+#[async_trait]
+impl ProxyPlugin for PluginHmacAuth {
+    fn name(&self) -> &str {
+        PLUGIN_NAME
+    }
+
+    fn priority(&self) -> i32 {
+        2530
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut ProxyContext) -> Result<bool> {
+        match self.verify(session) {
+            Ok(consumer) => {
+                if self.config.validate_request_body {
+                    let digest = header_value(session, "digest").unwrap_or_default();
+                    ctx.vars.insert(DIGEST_VAR.to_string(), digest.to_string());
+                }
+                attach_consumer(ctx, consumer);
+                Ok(false)
+            }
+            Err(e) => {
+                log::debug!("HMAC signature rejected: {}", e);
+                unauthorized(session, "Invalid HMAC signature in request", None).await
+            }
+        }
+    }
+
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut ProxyContext,
+    ) -> Result<()> {
+        let Some(digest) = ctx.vars.get(DIGEST_VAR) else {
+            return Ok(());
+        };
+
+        // The body is held back until its digest is checked. The request headers
+        // were sent by then, so a mismatch aborts a request the upstream has seen
+        // but whose body it never receives.
+        if let Some(chunk) = body.take() {
+            if ctx.request_body.len() + chunk.len() > self.config.max_req_body {
+                return Error::e_explain(
+                    ErrorType::HTTPStatus(413),
+                    "Request body too large to check its digest",
+                );
+            }
+            ctx.request_body.extend_from_slice(&chunk);
+        }
+
+        if !end_of_stream {
+            return Ok(());
+        }
+
+        if !verify_digest(digest, &ctx.request_body) {
+            return Error::e_explain(ErrorType::HTTPStatus(400), "Request body digest mismatch");
+        }
+
+        *body = Some(ctx.request_body.split().freeze());
+        Ok(())
+    }
+}
+
+impl PluginHmacAuth {
+    /// Verifies the signature of a request, returns its consumer.
+    fn verify(&self, session: &Session) -> Result<Arc<ProxyConsumer>> {
+        let access_key =
+            header_value(session, ACCESS_KEY_HEADER).or_err(ReadError, "Missing access key")?;
+        let signature = header_value(session, SIGNATURE_HEADER)
+            .and_then(|signature| STANDARD.decode(signature.trim()).ok())
+            .or_err(ReadError, "Missing or malformed signature")?;
+        let algorithm = header_value(session, ALGORITHM_HEADER)
+            .and_then(HmacAlgorithm::from_header)
+            .filter(|algorithm| self.config.allowed_algorithms.contains(algorithm))
+            .or_err(ReadError, "Missing or unsupported algorithm")?;
+
+        let date =
+            header_value(session, header::DATE.as_str()).or_err(ReadError, "Missing Date")?;
+        let date = httpdate::parse_http_date(date).or_err(ReadError, "Malformed Date")?;
+        let skew = match SystemTime::now().duration_since(date) {
+            Ok(elapsed) => elapsed,
+            Err(e) => e.duration(),
+        };
+        if skew > Duration::from_secs(self.config.clock_skew) {
+            return Error::e_explain(ReadError, "Date is out of the allowed clock skew");
+        }
+
+        let signed_headers: Vec<String> = header_value(session, SIGNED_HEADERS_HEADER)
+            .map(|value| {
+                value
+                    .split(';')
+                    .map(|name| name.trim().to_ascii_lowercase())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let required = self
+            .config
+            .signed_headers
+            .iter()
+            .map(|name| name.as_str())
+            .chain(self.config.validate_request_body.then_some("digest"));
+        for name in required {
+            if !signed_headers
+                .iter()
+                .any(|signed| signed.eq_ignore_ascii_case(name))
+            {
+                return Error::e_explain(ReadError, format!("Header {} must be signed", name));
+            }
+        }
+
+        let mut headers = Vec::with_capacity(signed_headers.len());
+        for name in &signed_headers {
+            let value = header_value(session, name)
+                .or_err_with(ReadError, || format!("Missing signed header {}", name))?;
+            headers.push((name.as_str(), value));
+        }
+
+        let consumer = consumer_fetch_by_credential(PLUGIN_NAME, access_key)
+            .or_err(ReadError, "No consumer matches the access key")?;
+        let secret_key = consumer
+            .credential(PLUGIN_NAME, "secret_key")
+            .or_err(ReadError, "Missing secret key of the consumer")?;
+
+        let req_header = session.req_header();
+        let signing_string = signing_string(
+            req_header.method.as_str(),
+            req_header.uri.path(),
+            req_header.uri.query().unwrap_or_default(),
+            access_key,
+            header_value(session, header::DATE.as_str()).unwrap_or_default(),
+            &headers,
+        );
+        if !algorithm.verify(secret_key.as_bytes(), &signing_string, &signature) {
+            return Error::e_explain(ReadError, "Signature mismatch");
+        }
+
+        self.check_replay(signature)?;
+
+        Ok(consumer)
+    }
+
+    /// Rejects signatures already seen within the clock skew.
+    fn check_replay(&self, signature: Vec<u8>) -> Result<()> {
+        // A replayed request may come from the other side of the clock skew
+        let ttl = Duration::from_secs(self.config.clock_skew * 2);
+        if !SIGNATURES
+            .lock()
+            .unwrap()
+            .insert(signature, ttl, Instant::now())
+        {
+            return Error::e_explain(ReadError, "Replayed signature");
+        }
+
+        Ok(())
+    }
+}
+
+/// Signatures seen recently, with the order they expire in.
+///
+/// When full the oldest signatures are forgotten first, so a replay of them is no
+/// longer detected.
+struct ReplayCache {
+    capacity: usize,
+    /// When each remembered signature expires
+    expires: HashMap<Vec<u8>, Instant>,
+    /// Signatures in insertion order
+    order: VecDeque<(Instant, Vec<u8>)>,
+}
+
+impl ReplayCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            expires: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remembers a signature for `ttl`, returns false if it is already remembered.
+    fn insert(&mut self, signature: Vec<u8>, ttl: Duration, now: Instant) -> bool {
+        // Routes may use different clock skews, so the queue is only roughly
+        // ordered by expiry, `expires` has the exact time of every signature.
+        while self
+            .order
+            .front()
+            .is_some_and(|(expires, _)| *expires <= now)
+        {
+            self.pop_front();
+        }
+
+        if self
+            .expires
+            .get(&signature)
+            .is_some_and(|expires| *expires > now)
+        {
+            return false;
+        }
+
+        while self.order.len() >= self.capacity {
+            self.pop_front();
+        }
+
+        let expires = now + ttl;
+        self.expires.insert(signature.clone(), expires);
+        self.order.push_back((expires, signature));
+        true
+    }
+
+    fn pop_front(&mut self) {
+        if let Some((expires, signature)) = self.order.pop_front() {
+            // The signature may have been seen again since
+            if self.expires.get(&signature) == Some(&expires) {
+                self.expires.remove(&signature);
+            }
+        }
+    }
+}
+
+fn header_value<'a>(session: &'a Session, name: &str) -> Option<&'a str> {
+    session
+        .req_header()
+        .headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Builds the string signed by clients.
+fn signing_string(
+    method: &str,
+    path: &str,
+    query: &str,
+    access_key: &str,
+    date: &str,
+    headers: &[(&str, &str)],
+) -> String {
+    let mut args: Vec<&str> = query.split('&').filter(|arg| !arg.is_empty()).collect();
+    args.sort_unstable();
+
+    let mut signing_string = format!(
+        "{}\n{}\n{}\n{}\n{}\n",
+        method,
+        path,
+        args.join("&"),
+        access_key,
+        date
+    );
+    for (name, value) in headers {
+        signing_string.push_str(&format!("{}:{}\n", name, value));
+    }
+
+    signing_string
+}
+
+/// Checks a `Digest` header, `SHA-256=<base64>` or `SHA-512=<base64>`, against the body.
+fn verify_digest(digest: &str, body: &[u8]) -> bool {
+    let Some((algorithm, value)) = digest.split_once('=') else {
+        return false;
+    };
+
+    let expected = match algorithm.trim().to_ascii_uppercase().as_str() {
+        "SHA-256" => STANDARD.encode(Sha256::digest(body)),
+        "SHA-512" => STANDARD.encode(Sha512::digest(body)),
+        _ => return false,
+    };
+
+    value.trim() == expected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config,
+        proxy::{
+            consumer::{reload_credential_index, CONSUMER_MAP},
+            plugin::test_session,
+            MapOperations,
+        },
+        service::http::{test_backend, test_proxy, test_request},
+    };
+
+    const ACCESS_KEY: &str = "hmac-auth-ak";
+    const SECRET_KEY: &str = "hmac-auth-sk";
+
+    fn plugin(cfg: &str) -> PluginHmacAuth {
+        let config: PluginConfig = serde_yaml::from_str(cfg).unwrap();
+        config.validate().unwrap();
+        PluginHmacAuth { config }
+    }
+
+    fn insert_consumer() {
+        let consumer = config::Consumer {
+            username: "hmac-auth-jack".to_string(),
+            plugins: serde_yaml::from_str(&format!(
+                "hmac-auth: {{access_key: {}, secret_key: {}}}",
+                ACCESS_KEY, SECRET_KEY
+            ))
+            .unwrap(),
+            ..Default::default()
+        };
+        CONSUMER_MAP.insert(Arc::new(ProxyConsumer::new_with_plugins(consumer).unwrap()));
+        reload_credential_index();
+    }
+
+    /// Signs a request with SHA-256, `headers` are signed in order. Returns the
+    /// `Date` and signature headers to send along with `headers`.
+    fn sign(
+        method: &str,
+        path: &str,
+        date: SystemTime,
+        headers: &[(&str, &str)],
+    ) -> Vec<(&'static str, String)> {
+        let date = httpdate::fmt_http_date(date);
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        let signing_string = signing_string(method, path, query, ACCESS_KEY, &date, headers);
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET_KEY.as_bytes()).unwrap();
+        mac.update(signing_string.as_bytes());
+        let signature = STANDARD.encode(mac.finalize().into_bytes());
+
+        let names: Vec<&str> = headers.iter().map(|(name, _)| *name).collect();
+        vec![
+            ("Date", date),
+            (ACCESS_KEY_HEADER, ACCESS_KEY.to_string()),
+            (SIGNATURE_HEADER, signature),
+            (ALGORITHM_HEADER, "hmac-sha256".to_string()),
+            (SIGNED_HEADERS_HEADER, names.join(";")),
+        ]
+    }
+
+    /// Builds a GET request signed with SHA-256, `headers` are signed in order.
+    fn signed_request(path: &str, date: SystemTime, headers: &[(&str, &str)]) -> String {
+        let mut request = format!("GET {} HTTP/1.1\r\n", path);
+        for (name, value) in sign("GET", path, date, headers) {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        request
+    }
+
+    async fn verify(plugin: &PluginHmacAuth, request: &str) -> Result<Arc<ProxyConsumer>> {
+        let (session, _client) = test_session(request).await;
+        plugin.verify(&session)
+    }
+
+    #[test]
+    fn test_signing_string() {
+        assert_eq!(
+            signing_string(
+                "POST",
+                "/orders",
+                "b=2&a=1&&c",
+                "ak",
+                "Thu, 15 Oct 2026 10:00:00 GMT",
+                &[("x-custom", "v"), ("digest", "SHA-256=abc")],
+            ),
+            "POST\n/orders\na=1&b=2&c\nak\nThu, 15 Oct 2026 10:00:00 GMT\nx-custom:v\ndigest:SHA-256=abc\n"
+        );
+        assert_eq!(
+            signing_string("GET", "/", "", "ak", "date", &[]),
+            "GET\n/\n\nak\ndate\n"
+        );
+    }
+
+    #[test]
+    fn test_verify_digest() {
+        let body = b"hello";
+        let sha256 = format!("SHA-256={}", STANDARD.encode(Sha256::digest(body)));
+        let sha512 = format!("sha-512={}", STANDARD.encode(Sha512::digest(body)));
+
+        assert!(verify_digest(&sha256, body));
+        assert!(verify_digest(&sha512, body));
+        assert!(!verify_digest(&sha256, b"hello!"));
+        assert!(!verify_digest("MD5=XUFAKrxLKna5cZ2REBfFkg==", body));
+        assert!(!verify_digest("SHA-256", body));
+    }
+
+    #[tokio::test]
+    async fn test_verify_signature() {
+        insert_consumer();
+        let plugin = plugin("signed_headers: [x-custom]");
+        let headers = [("x-custom", "v")];
+
+        let request = signed_request("/orders?b=2&a=1", SystemTime::now(), &headers);
+        let consumer = verify(&plugin, &request).await.unwrap();
+        assert_eq!(consumer.inner.username, "hmac-auth-jack");
+
+        // The same signature can't be used twice
+        assert!(verify(&plugin, &request).await.is_err());
+
+        // Tampering with a signed header
+        let request = signed_request("/orders", SystemTime::now(), &headers);
+        assert!(
+            verify(&plugin, &request.replace("x-custom: v", "x-custom: w"))
+                .await
+                .is_err()
+        );
+
+        // Required headers must be signed
+        let request = signed_request("/orders", SystemTime::now(), &[]);
+        assert!(verify(&plugin, &request).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_clock_skew() {
+        insert_consumer();
+        let plugin = plugin("clock_skew: 60");
+
+        let past = SystemTime::now() - Duration::from_secs(120);
+        assert!(verify(&plugin, &signed_request("/", past, &[]))
+            .await
+            .is_err());
+
+        let future = SystemTime::now() + Duration::from_secs(120);
+        assert!(verify(&plugin, &signed_request("/", future, &[]))
+            .await
+            .is_err());
+
+        let recent = SystemTime::now() - Duration::from_secs(30);
+        assert!(verify(&plugin, &signed_request("/", recent, &[]))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_verify_algorithm() {
+        insert_consumer();
+        let plugin = plugin("allowed_algorithms: [hmac-sha512]");
+
+        let request = signed_request("/", SystemTime::now(), &[]);
+        assert!(verify(&plugin, &request).await.is_err());
+        assert!(verify(&plugin, &request.replace("hmac-sha256", "hmac-md5"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_body_filter() {
+        let plugin = plugin("{validate_request_body: true, max_req_body: 8}");
+        let (mut session, _client) = test_session("POST / HTTP/1.1\r\n\r\n").await;
+
+        let mut ctx = ProxyContext::default();
+        let digest = format!("SHA-256={}", STANDARD.encode(Sha256::digest(b"hello")));
+        ctx.vars.insert(DIGEST_VAR.to_string(), digest);
+
+        // The body is held back until the end of the stream
+        let mut body = Some(Bytes::from_static(b"hel"));
+        plugin
+            .request_body_filter(&mut session, &mut body, false, &mut ctx)
+            .await
+            .unwrap();
+        assert!(body.is_none());
+
+        let mut body = Some(Bytes::from_static(b"lo"));
+        plugin
+            .request_body_filter(&mut session, &mut body, true, &mut ctx)
+            .await
+            .unwrap();
+        assert_eq!(body.as_deref(), Some(&b"hello"[..]));
+
+        let mut body = Some(Bytes::from_static(b"hello!"));
+        let err = plugin
+            .request_body_filter(&mut session, &mut body, true, &mut ctx)
+            .await
+            .unwrap_err();
+        assert_eq!(err.etype, ErrorType::HTTPStatus(400));
+
+        let mut ctx = ProxyContext::default();
+        ctx.vars.insert(DIGEST_VAR.to_string(), String::new());
+        let mut body = Some(Bytes::from_static(b"too large body"));
+        let err = plugin
+            .request_body_filter(&mut session, &mut body, false, &mut ctx)
+            .await
+            .unwrap_err();
+        assert_eq!(err.etype, ErrorType::HTTPStatus(413));
+    }
+
+    #[tokio::test]
+    async fn test_digest_mismatch_status() {
+        insert_consumer();
+        let (upstream, _) = test_backend(200, Duration::ZERO).await;
+        let proxy = test_proxy(&format!(
+            r#"
+id: hmac-auth-digest
+uri: /hmac-auth-digest
+plugins:
+  hmac-auth: {{validate_request_body: true}}
+upstream:
+  nodes:
+    - {{host: 127.0.0.1, port: {upstream}, weight: 1}}
+"#
+        ))
+        .await;
+
+        // Signs the digest of `signed` and sends `body`
+        async fn send(proxy: &str, body: &[u8], signed: &[u8]) -> u16 {
+            let digest = format!("SHA-256={}", STANDARD.encode(Sha256::digest(signed)));
+            let headers = [("digest", digest.as_str())];
+            let signature = sign("POST", "/hmac-auth-digest", SystemTime::now(), &headers);
+            let headers: Vec<(&str, &str)> = signature
+                .iter()
+                .map(|(name, value)| (*name, value.as_str()))
+                .chain(headers)
+                .collect();
+            test_request(proxy, "POST", "/hmac-auth-digest", &headers, body).await
+        }
+
+        let body = b"{\"amount\": 1}";
+        assert_eq!(send(&proxy, body, body).await, 200);
+        // The upstream has seen the request headers, the client still gets a 400
+        assert_eq!(
+            send(&proxy, b"{\"amount\": 100}", b"{\"amount\": 2}").await,
+            400
+        );
+    }
+
+    #[test]
+    fn test_replay_cache() {
+        let ttl = Duration::from_secs(10);
+        let now = Instant::now();
+        let mut cache = ReplayCache::new(2);
+
+        assert!(cache.insert(b"a".to_vec(), ttl, now));
+        assert!(!cache.insert(b"a".to_vec(), ttl, now + Duration::from_secs(5)));
+
+        // Expired signatures are forgotten
+        assert!(cache.insert(b"a".to_vec(), ttl, now + Duration::from_secs(11)));
+        assert_eq!(cache.order.len(), 1);
+
+        // The oldest signature makes room when full
+        let now = now + Duration::from_secs(11);
+        assert!(cache.insert(b"b".to_vec(), ttl, now));
+        assert!(cache.insert(b"c".to_vec(), ttl, now));
+        assert_eq!(cache.order.len(), 2);
+        assert!(cache.insert(b"a".to_vec(), ttl, now));
+        assert!(!cache.insert(b"c".to_vec(), ttl, now));
+    }
+
+    #[test]
+    fn test_replay_cache_mixed_ttl() {
+        let now = Instant::now();
+        let mut cache = ReplayCache::new(10);
+
+        // A long lived signature ahead of the queue keeps an expired one queued
+        assert!(cache.insert(b"long".to_vec(), Duration::from_secs(100), now));
+        assert!(cache.insert(b"short".to_vec(), Duration::from_secs(1), now));
+
+        let later = now + Duration::from_secs(2);
+        assert!(cache.insert(b"short".to_vec(), Duration::from_secs(10), later));
+        assert!(!cache.insert(b"short".to_vec(), Duration::from_secs(10), later));
+        assert!(!cache.insert(b"long".to_vec(), Duration::from_secs(100), later));
+    }
+}
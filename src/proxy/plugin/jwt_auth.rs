@@ -0,0 +1,694 @@
+use std::{collections::HashMap, fs, sync::Arc};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http::{header, HeaderName, HeaderValue};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use pingora_error::{Error, ErrorType::ReadError, OkOrErr, OrErr, Result};
+use pingora_http::RequestHeader;
+use pingora_proxy::Session;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+use serde_yaml::Value as YamlValue;
+use validator::{Validate, ValidationError};
+
+use crate::proxy::{
+    consumer::{consumer_fetch_by_credential, ProxyConsumer},
+    find_cookie_value, get_query_value, remove_query_arg, ProxyContext,
+};
+
+use super::{attach_consumer, unauthorized, ProxyPlugin};
+
+pub const PLUGIN_NAME: &str = "jwt-auth";
+
+const CHALLENGE: &str = "Bearer realm=\"pingsix\"";
+
+/// Creates a JWT Auth plugin instance.
+pub fn create_jwt_auth_plugin(cfg: YamlValue) -> Result<Arc<dyn ProxyPlugin>> {
+    let config: PluginConfig =
+        serde_yaml::from_value(cfg).or_err_with(ReadError, || "Invalid jwt auth plugin config")?;
+
+    config
+        .validate()
+        .or_err_with(ReadError, || "Invalid jwt auth plugin config")?;
+
+    // Consumer keys are built when consumers load, this checks them for validation
+    if config.key.is_some() {
+        config.decoding_key()?;
+    }
+
+    let jwks = match config.jwks_file {
+        Some(ref path) => load_jwks(path)?,
+        None => HashMap::new(),
+    };
+
+    Ok(Arc::new(PluginJwtAuth { config, jwks }))
+}
+
+/// Key verifying the tokens of a consumer.
+pub struct ConsumerKey {
+    decoding_key: DecodingKey,
+    algorithm: Algorithm,
+}
+
+/// Builds the key of a consumer from its jwt auth configuration, `None` if the
+/// configuration has no key claim value.
+pub fn create_consumer_key(cfg: YamlValue) -> Result<Option<ConsumerKey>> {
+    let config: PluginConfig =
+        serde_yaml::from_value(cfg).or_err_with(ReadError, || "Invalid jwt auth plugin config")?;
+
+    if config.key.is_none() {
+        return Ok(None);
+    }
+
+    Ok(Some(ConsumerKey {
+        decoding_key: config.decoding_key()?,
+        algorithm: config.algorithm,
+    }))
+}
+
+/// Configuration for the JWT Auth plugin.
+///
+/// On routes it tells where the token is read from and how it is validated, on
+/// consumers it holds the key claim value and the key verifying the signature.
+#[derive(Debug, Serialize, Deserialize, Validate)]
+#[validate(schema(function = "PluginConfig::validate_key"))]
+struct PluginConfig {
+    /// Header carrying the token, with or without the `Bearer` prefix.
+    #[serde(default = "PluginConfig::default_header")]
+    #[validate(length(min = 1))]
+    header: String,
+    /// Query argument carrying the token, used when the header is missing.
+    #[serde(default = "PluginConfig::default_query")]
+    #[validate(length(min = 1))]
+    query: String,
+    /// Cookie carrying the token, used when the query argument is missing.
+    #[serde(default = "PluginConfig::default_cookie")]
+    #[validate(length(min = 1))]
+    cookie: String,
+    /// Removes the token from the request sent to the upstream.
+    #[serde(default)]
+    hide_credentials: bool,
+    /// Claim holding the key of the consumer.
+    #[serde(default = "PluginConfig::default_key_claim_name")]
+    #[validate(length(min = 1))]
+    key_claim_name: String,
+    /// Seconds of clock skew tolerated when checking `exp` and `nbf`.
+    #[serde(default)]
+    clock_skew: u64,
+    /// Accepted `iss` claims, the claim is required when set.
+    #[serde(default)]
+    issuer: Vec<String>,
+    /// Accepted `aud` claims, the claim is required when set.
+    #[serde(default)]
+    audience: Vec<String>,
+    /// Claims forwarded to the upstream, keyed by claim with the header name as value.
+    #[serde(default)]
+    forward_claims: HashMap<String, String>,
+    /// Local JWKS file, tokens whose `kid` is found there are verified with its keys.
+    #[validate(length(min = 1))]
+    jwks_file: Option<String>,
+
+    /// Key claim value of a consumer.
+    #[validate(length(min = 1))]
+    key: Option<String>,
+    /// Signing algorithm of a consumer, one of HS256, RS256 or ES256.
+    #[serde(default = "PluginConfig::default_algorithm")]
+    algorithm: Algorithm,
+    /// HMAC secret of a consumer, for HS256.
+    secret: Option<String>,
+    /// Whether `secret` is base64 encoded.
+    #[serde(default)]
+    base64_secret: bool,
+    /// PEM public key of a consumer, for RS256 and ES256.
+    public_key: Option<String>,
+}
+
+impl PluginConfig {
+    fn default_header() -> String {
+        "authorization".to_string()
+    }
+
+    fn default_query() -> String {
+        "jwt".to_string()
+    }
+
+    fn default_cookie() -> String {
+        "jwt".to_string()
+    }
+
+    fn default_key_claim_name() -> String {
+        "key".to_string()
+    }
+
+    fn default_algorithm() -> Algorithm {
+        Algorithm::HS256
+    }
+
+    fn validate_key(&self) -> Result<(), ValidationError> {
+        if self
+            .forward_claims
+            .values()
+            .any(|name| HeaderName::try_from(name.as_str()).is_err())
+        {
+            return Err(ValidationError::new("invalid_forward_claims_header"));
+        }
+
+        if !is_supported_algorithm(self.algorithm) {
+            return Err(ValidationError::new("unsupported_algorithm"));
+        }
+
+        if self.key.is_none() {
+            return Ok(());
+        }
+
+        match self.algorithm {
+            Algorithm::HS256 if self.secret.is_none() => {
+                Err(ValidationError::new("secret_required"))
+            }
+            Algorithm::RS256 | Algorithm::ES256 if self.public_key.is_none() => {
+                Err(ValidationError::new("public_key_required"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Builds the key verifying the tokens of a consumer.
+    fn decoding_key(&self) -> Result<DecodingKey> {
+        match self.algorithm {
+            Algorithm::HS256 => {
+                let secret = self.secret.as_deref().unwrap_or_default();
+                if self.base64_secret {
+                    DecodingKey::from_base64_secret(secret)
+                        .or_err(ReadError, "Invalid base64 secret in jwt auth config")
+                } else {
+                    Ok(DecodingKey::from_secret(secret.as_bytes()))
+                }
+            }
+            Algorithm::RS256 => {
+                DecodingKey::from_rsa_pem(self.public_key.as_deref().unwrap_or_default().as_bytes())
+                    .or_err(ReadError, "Invalid RSA public key in jwt auth config")
+            }
+            _ => {
+                DecodingKey::from_ec_pem(self.public_key.as_deref().unwrap_or_default().as_bytes())
+                    .or_err(ReadError, "Invalid EC public key in jwt auth config")
+            }
+        }
+    }
+}
+
+/// JWT Auth plugin implementation.
+///
+/// Authenticates the consumer whose key is found in the key claim of a token
+/// signed with the consumer key, or with a key of the JWKS file.
+pub struct PluginJwtAuth {
+    config: PluginConfig,
+    /// Keys of the JWKS file and their algorithm, keyed by `kid`
+    jwks: HashMap<String, (DecodingKey, Algorithm)>,
+}
+
+#[async_trait]
+impl ProxyPlugin for PluginJwtAuth {
+    fn name(&self) -> &str {
+        PLUGIN_NAME
+    }
+
+    fn priority(&self) -> i32 {
+        2510
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut ProxyContext) -> Result<bool> {
+        let Some(token) = self.token(session.req_header()) else {
+            return unauthorized(session, "Missing JWT token in request", Some(CHALLENGE)).await;
+        };
+
+        let (consumer, claims) = match self.verify(&token) {
+            Ok(verified) => verified,
+            Err(e) => {
+                log::debug!("JWT token rejected: {}", e);
+                return unauthorized(session, "Invalid JWT token in request", Some(CHALLENGE))
+                    .await;
+            }
+        };
+
+        for claim in self.config.forward_claims.keys() {
+            if let Some(value) = claims.get(claim) {
+                let value = match value {
+                    JsonValue::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                // Claims that can't be a header value are not forwarded
+                if HeaderValue::from_str(&value).is_err() {
+                    log::debug!("Skipping JWT claim {} not valid as a header value", claim);
+                    continue;
+                }
+                ctx.vars.insert(format!("jwt_claim_{}", claim), value);
+            }
+        }
+
+        attach_consumer(ctx, consumer);
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut ProxyContext,
+    ) -> Result<()> {
+        for (claim, header_name) in &self.config.forward_claims {
+            // Headers sent by the client must not pass for verified claims
+            upstream_request.remove_header(header_name.as_str());
+            if let Some(value) = ctx.vars.get(&format!("jwt_claim_{}", claim)) {
+                upstream_request.insert_header(header_name.clone(), value)?;
+            }
+        }
+
+        if !self.config.hide_credentials {
+            return Ok(());
+        }
+
+        upstream_request.remove_header(self.config.header.as_str());
+        if let Some(uri) = remove_query_arg(&upstream_request.uri, &self.config.query) {
+            upstream_request.set_uri(uri);
+        }
+        if let Some(cookie) = remove_cookie(upstream_request, &self.config.cookie) {
+            if cookie.is_empty() {
+                upstream_request.remove_header(&header::COOKIE);
+            } else {
+                upstream_request.insert_header(header::COOKIE, cookie)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PluginJwtAuth {
+    /// Reads the token from the header, the query argument or the cookie, in this order.
+    fn token(&self, req_header: &RequestHeader) -> Option<String> {
+        if let Some(value) = req_header
+            .headers
+            .get(self.config.header.as_str())
+            .and_then(|value| value.to_str().ok())
+        {
+            let value = value.trim();
+            let token = match value.split_once(' ') {
+                Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+                _ => value,
+            };
+            return Some(token.to_string());
+        }
+
+        get_query_value(req_header, &self.config.query)
+            .or_else(|| find_cookie_value(req_header, &self.config.cookie))
+            .map(|token| token.to_string())
+    }
+
+    /// Verifies a token, returns its consumer and claims.
+    fn verify(&self, token: &str) -> Result<(Arc<ProxyConsumer>, Map<String, JsonValue>)> {
+        let header = decode_header(token).or_err(ReadError, "Invalid JWT header")?;
+
+        // The consumer is known before verifying the signature, its key may be required
+        let key = unverified_claims(token)?
+            .get(&self.config.key_claim_name)
+            .and_then(|key| key.as_str().map(|key| key.to_string()))
+            .or_err(ReadError, "Missing key claim in JWT token")?;
+        let consumer = consumer_fetch_by_credential(PLUGIN_NAME, &key).or_err(
+            ReadError,
+            "No consumer matches the key claim of the JWT token",
+        )?;
+
+        let jwk = header.kid.as_ref().and_then(|kid| self.jwks.get(kid));
+        let (decoding_key, algorithm) = match jwk {
+            Some((decoding_key, algorithm)) => (decoding_key, *algorithm),
+            None => {
+                let key = consumer
+                    .jwt_key
+                    .as_ref()
+                    .or_err(ReadError, "Missing jwt auth consumer key")?;
+                (&key.decoding_key, key.algorithm)
+            }
+        };
+
+        let claims =
+            decode::<Map<String, JsonValue>>(token, decoding_key, &self.validation(algorithm))
+                .or_err(ReadError, "Invalid JWT token")?
+                .claims;
+
+        Ok((consumer, claims))
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.config.clock_skew;
+        validation.validate_nbf = true;
+
+        let mut required = vec!["exp"];
+        if !self.config.issuer.is_empty() {
+            validation.set_issuer(&self.config.issuer);
+            required.push("iss");
+        }
+        if self.config.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.config.audience);
+            required.push("aud");
+        }
+        validation.set_required_spec_claims(&required);
+
+        validation
+    }
+}
+
+fn is_supported_algorithm(algorithm: Algorithm) -> bool {
+    matches!(
+        algorithm,
+        Algorithm::HS256 | Algorithm::RS256 | Algorithm::ES256
+    )
+}
+
+/// Decodes the claims of a token without verifying it.
+fn unverified_claims(token: &str) -> Result<Map<String, JsonValue>> {
+    let payload = token
+        .split('.')
+        .nth(1)
+        .or_err(ReadError, "Malformed JWT token")?;
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload)
+        .or_err(ReadError, "Malformed JWT payload")?;
+
+    serde_json::from_slice(&payload).or_err(ReadError, "Malformed JWT claims")
+}
+
+/// Loads the keys of a JWKS file, keys without `kid` are skipped.
+fn load_jwks(path: &str) -> Result<HashMap<String, (DecodingKey, Algorithm)>> {
+    let content = fs::read_to_string(path)
+        .or_err_with(ReadError, || format!("Failed to read JWKS file {}", path))?;
+    let jwks: JwkSet = serde_json::from_str(&content)
+        .or_err_with(ReadError, || format!("Invalid JWKS file {}", path))?;
+
+    let mut keys = HashMap::new();
+    for jwk in jwks.keys {
+        let Some(kid) = jwk.common.key_id.clone() else {
+            log::warn!("Skipping JWKS key without kid in {}", path);
+            continue;
+        };
+
+        let algorithm = match jwk.algorithm {
+            AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+            AlgorithmParameters::RSA(_) => Algorithm::RS256,
+            AlgorithmParameters::EllipticCurve(_) => Algorithm::ES256,
+            AlgorithmParameters::OctetKeyPair(_) => {
+                return Error::e_explain(
+                    ReadError,
+                    format!("Unsupported key type of JWKS key {} in {}", kid, path),
+                )
+            }
+        };
+        let decoding_key = DecodingKey::from_jwk(&jwk).or_err_with(ReadError, || {
+            format!("Invalid JWKS key {} in {}", kid, path)
+        })?;
+
+        keys.insert(kid, (decoding_key, algorithm));
+    }
+
+    Ok(keys)
+}
+
+/// Removes a cookie from the `Cookie` header, returns `None` when it is absent.
+fn remove_cookie(req_header: &RequestHeader, name: &str) -> Option<String> {
+    let cookie = req_header.headers.get(header::COOKIE)?.to_str().ok()?;
+    let is_cookie = |item: &&str| item.split('=').next().map(str::trim) == Some(name);
+    if !cookie.split(';').any(|item| is_cookie(&item)) {
+        return None;
+    }
+
+    Some(
+        cookie
+            .split(';')
+            .filter(|item| !is_cookie(item))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+            .join("; "),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        config,
+        proxy::{
+            consumer::{reload_credential_index, CONSUMER_MAP},
+            plugin::test_session,
+            MapOperations,
+        },
+    };
+
+    const SECRET: &str = "jwt-auth-secret";
+
+    fn plugin(cfg: &str) -> PluginJwtAuth {
+        let config: PluginConfig = serde_yaml::from_str(cfg).unwrap();
+        config.validate().unwrap();
+        let jwks = match config.jwks_file {
+            Some(ref path) => load_jwks(path).unwrap(),
+            None => HashMap::new(),
+        };
+        PluginJwtAuth { config, jwks }
+    }
+
+    fn insert_consumer() {
+        let consumer = config::Consumer {
+            username: "jwt-auth-jack".to_string(),
+            plugins: serde_yaml::from_str(&format!(
+                "jwt-auth: {{key: jwt-auth-key, secret: {}}}",
+                SECRET
+            ))
+            .unwrap(),
+            ..Default::default()
+        };
+        CONSUMER_MAP.insert(Arc::new(ProxyConsumer::new_with_plugins(consumer).unwrap()));
+        reload_credential_index();
+    }
+
+    fn token(header: Header, claims: JsonValue, secret: &str) -> String {
+        encode(
+            &header,
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn claims_with(extra: JsonValue) -> JsonValue {
+        let mut claims = json!({
+            "key": "jwt-auth-key",
+            "exp": get_current_timestamp() + 60,
+        });
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        claims
+    }
+
+    #[test]
+    fn test_verify_consumer_key() {
+        insert_consumer();
+        let plugin = plugin("{}");
+
+        let (consumer, claims) = plugin
+            .verify(&token(Header::default(), claims_with(json!({})), SECRET))
+            .unwrap();
+        assert_eq!(consumer.inner.username, "jwt-auth-jack");
+        assert_eq!(claims["key"], "jwt-auth-key");
+
+        assert!(plugin
+            .verify(&token(Header::default(), claims_with(json!({})), "other"))
+            .is_err());
+
+        // The algorithm of the consumer key is enforced
+        let header = Header::new(Algorithm::HS512);
+        assert!(plugin
+            .verify(&token(header, claims_with(json!({})), SECRET))
+            .is_err());
+
+        let no_key = json!({ "exp": get_current_timestamp() + 60 });
+        assert!(plugin
+            .verify(&token(Header::default(), no_key, SECRET))
+            .is_err());
+
+        let unknown_key = claims_with(json!({ "key": "jwt-auth-unknown" }));
+        assert!(plugin
+            .verify(&token(Header::default(), unknown_key, SECRET))
+            .is_err());
+    }
+
+    #[test]
+    fn test_verify_exp_and_nbf() {
+        insert_consumer();
+        let now = get_current_timestamp();
+        let expired = claims_with(json!({ "exp": now - 30 }));
+        let not_before = claims_with(json!({ "nbf": now + 30 }));
+        let no_exp = json!({ "key": "jwt-auth-key" });
+
+        let plugin = self::plugin("{}");
+        assert!(plugin
+            .verify(&token(Header::default(), expired.clone(), SECRET))
+            .is_err());
+        assert!(plugin
+            .verify(&token(Header::default(), not_before.clone(), SECRET))
+            .is_err());
+        assert!(plugin
+            .verify(&token(Header::default(), no_exp, SECRET))
+            .is_err());
+
+        // Within the clock skew
+        let plugin = self::plugin("clock_skew: 60");
+        assert!(plugin
+            .verify(&token(Header::default(), expired, SECRET))
+            .is_ok());
+        assert!(plugin
+            .verify(&token(Header::default(), not_before, SECRET))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_verify_issuer_and_audience() {
+        insert_consumer();
+        let plugin = plugin("{issuer: [pingsix], audience: [api, web]}");
+
+        let valid = claims_with(json!({ "iss": "pingsix", "aud": "web" }));
+        assert!(plugin
+            .verify(&token(Header::default(), valid, SECRET))
+            .is_ok());
+
+        for invalid in [
+            json!({ "iss": "other", "aud": "web" }),
+            json!({ "iss": "pingsix", "aud": "other" }),
+            json!({ "aud": "web" }),
+            json!({ "iss": "pingsix" }),
+        ] {
+            assert!(plugin
+                .verify(&token(Header::default(), claims_with(invalid), SECRET))
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_verify_jwks_kid() {
+        insert_consumer();
+        let path = std::env::temp_dir().join(format!("pingsix-jwks-{}.json", std::process::id()));
+        let jwks = json!({
+            "keys": [
+                { "kty": "oct", "kid": "first", "k": URL_SAFE_NO_PAD.encode("jwks-first") },
+                { "kty": "oct", "kid": "second", "k": URL_SAFE_NO_PAD.encode("jwks-second") },
+            ]
+        });
+        fs::write(&path, jwks.to_string()).unwrap();
+        let plugin = plugin(&format!("jwks_file: {}", path.display()));
+        fs::remove_file(&path).unwrap();
+
+        let header = Header {
+            kid: Some("second".to_string()),
+            ..Default::default()
+        };
+        assert!(plugin
+            .verify(&token(
+                header.clone(),
+                claims_with(json!({})),
+                "jwks-second"
+            ))
+            .is_ok());
+        assert!(plugin
+            .verify(&token(header, claims_with(json!({})), "jwks-first"))
+            .is_err());
+
+        // Unknown kids fall back to the consumer key
+        let header = Header {
+            kid: Some("unknown".to_string()),
+            ..Default::default()
+        };
+        assert!(plugin
+            .verify(&token(header, claims_with(json!({})), SECRET))
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_forward_claims() {
+        insert_consumer();
+        let plugin = plugin("forward_claims: {sub: x-user, note: x-note, role: x-role}");
+        let claims = claims_with(json!({ "sub": "jack", "note": "two\nlines" }));
+        let request = format!(
+            "GET / HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+            token(Header::default(), claims, SECRET)
+        );
+
+        let (mut session, _client) = test_session(&request).await;
+        let mut ctx = ProxyContext::default();
+        assert!(!plugin.request_filter(&mut session, &mut ctx).await.unwrap());
+
+        // Forged claim headers are replaced or removed
+        let mut upstream_request = RequestHeader::build("GET", b"/", None).unwrap();
+        for name in ["x-user", "x-note", "x-role"] {
+            upstream_request.insert_header(name, "admin").unwrap();
+        }
+        plugin
+            .upstream_request_filter(&mut session, &mut upstream_request, &mut ctx)
+            .await
+            .unwrap();
+        assert_eq!(upstream_request.headers["x-user"], "jack");
+        assert!(upstream_request.headers.get("x-note").is_none());
+        assert!(upstream_request.headers.get("x-role").is_none());
+    }
+
+    #[test]
+    fn test_token_lookup() {
+        let plugin = plugin("{}");
+
+        let mut req_header = RequestHeader::build("GET", b"/?jwt=from-query", None).unwrap();
+        req_header
+            .insert_header(header::COOKIE, "a=1; jwt=from-cookie")
+            .unwrap();
+        assert_eq!(plugin.token(&req_header).as_deref(), Some("from-query"));
+
+        req_header
+            .insert_header(header::AUTHORIZATION, "Bearer from-header")
+            .unwrap();
+        assert_eq!(plugin.token(&req_header).as_deref(), Some("from-header"));
+
+        let mut req_header = RequestHeader::build("GET", b"/", None).unwrap();
+        req_header
+            .insert_header(header::COOKIE, "a=1; jwt=from-cookie")
+            .unwrap();
+        assert_eq!(plugin.token(&req_header).as_deref(), Some("from-cookie"));
+    }
+
+    #[test]
+    fn test_remove_cookie() {
+        let mut req_header = RequestHeader::build("GET", b"/", None).unwrap();
+        assert_eq!(remove_cookie(&req_header, "jwt"), None);
+
+        req_header
+            .insert_header(header::COOKIE, "a=1; jwt=token; b=2")
+            .unwrap();
+        assert_eq!(
+            remove_cookie(&req_header, "jwt").as_deref(),
+            Some("a=1; b=2")
+        );
+        assert_eq!(remove_cookie(&req_header, "jw"), None);
+
+        req_header
+            .insert_header(header::COOKIE, "jwt=token")
+            .unwrap();
+        assert_eq!(remove_cookie(&req_header, "jwt").as_deref(), Some(""));
+    }
+}
@@ -0,0 +1,206 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use pingora_error::{ErrorType::ReadError, OrErr, Result};
+use pingora_http::RequestHeader;
+use pingora_proxy::Session;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value as YamlValue;
+use validator::Validate;
+
+use crate::proxy::{
+    consumer::consumer_fetch_by_credential, get_query_value, remove_query_arg, ProxyContext,
+};
+
+use super::{attach_consumer, unauthorized, ProxyPlugin};
+
+pub const PLUGIN_NAME: &str = "key-auth";
+
+/// Creates a Key Auth plugin instance.
+pub fn create_key_auth_plugin(cfg: YamlValue) -> Result<Arc<dyn ProxyPlugin>> {
+    let config: PluginConfig =
+        serde_yaml::from_value(cfg).or_err_with(ReadError, || "Invalid key auth plugin config")?;
+
+    config
+        .validate()
+        .or_err_with(ReadError, || "Invalid key auth plugin config")?;
+
+    Ok(Arc::new(PluginKeyAuth { config }))
+}
+
+/// Configuration for the Key Auth plugin.
+///
+/// On routes it tells where the key is read from, on consumers it holds the key.
+#[derive(Debug, Serialize, Deserialize, Validate)]
+struct PluginConfig {
+    /// Header carrying the key.
+    #[serde(default = "PluginConfig::default_header")]
+    #[validate(length(min = 1))]
+    header: String,
+    /// Query argument carrying the key, used when the header is missing.
+    #[serde(default = "PluginConfig::default_query")]
+    #[validate(length(min = 1))]
+    query: String,
+    /// Removes the key from the request sent to the upstream.
+    #[serde(default)]
+    hide_credentials: bool,
+
+    /// Key of a consumer.
+    #[validate(length(min = 1))]
+    key: Option<String>,
+}
+
+impl PluginConfig {
+    fn default_header() -> String {
+        "apikey".to_string()
+    }
+
+    fn default_query() -> String {
+        "apikey".to_string()
+    }
+}
+
+/// Key Auth plugin implementation.
+///
+/// Authenticates the consumer owning the key found in the request.
+pub struct PluginKeyAuth {
+    config: PluginConfig,
+}
+
+#[async_trait]
+impl ProxyPlugin for PluginKeyAuth {
+    fn name(&self) -> &str {
+        PLUGIN_NAME
+    }
+
+    fn priority(&self) -> i32 {
+        2500
+    }
+
+    async fn request_filter(&self, session: &mut Session, ctx: &mut ProxyContext) -> Result<bool> {
+        let key = session
+            .get_header(self.config.header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .or_else(|| get_query_value(session.req_header(), &self.config.query))
+            .map(|key| key.to_string());
+
+        let Some(key) = key else {
+            return unauthorized(session, "Missing API key in request", None).await;
+        };
+
+        match consumer_fetch_by_credential(PLUGIN_NAME, &key) {
+            Some(consumer) => {
+                attach_consumer(ctx, consumer);
+                Ok(false)
+            }
+            None => unauthorized(session, "Invalid API key in request", None).await,
+        }
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        _ctx: &mut ProxyContext,
+    ) -> Result<()> {
+        if !self.config.hide_credentials {
+            return Ok(());
+        }
+
+        upstream_request.remove_header(self.config.header.as_str());
+        if let Some(uri) = remove_query_arg(&upstream_request.uri, &self.config.query) {
+            upstream_request.set_uri(uri);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config,
+        proxy::{
+            consumer::{reload_credential_index, ProxyConsumer, CONSUMER_MAP},
+            plugin::{test_response, test_session},
+            MapOperations,
+        },
+    };
+
+    fn plugin(cfg: &str) -> Arc<dyn ProxyPlugin> {
+        create_key_auth_plugin(serde_yaml::from_str(cfg).unwrap()).unwrap()
+    }
+
+    fn insert_consumer(username: &str, key: &str) {
+        let consumer = config::Consumer {
+            username: username.to_string(),
+            plugins: serde_yaml::from_str(&format!("key-auth: {{key: {}}}", key)).unwrap(),
+            ..Default::default()
+        };
+        CONSUMER_MAP.insert(Arc::new(ProxyConsumer::new_with_plugins(consumer).unwrap()));
+        reload_credential_index();
+    }
+
+    #[tokio::test]
+    async fn test_key_from_header_and_query() {
+        insert_consumer("key-auth-jack", "key-auth-jack-key");
+        let plugin = plugin("header: x-key\nquery: key");
+
+        let (mut session, _client) =
+            test_session("GET / HTTP/1.1\r\nx-key: key-auth-jack-key\r\n\r\n").await;
+        let mut ctx = ProxyContext::default();
+        assert!(!plugin.request_filter(&mut session, &mut ctx).await.unwrap());
+        assert_eq!(ctx.vars["consumer_name"], "key-auth-jack");
+
+        let (mut session, _client) =
+            test_session("GET /?a=1&key=key-auth-jack-key HTTP/1.1\r\n\r\n").await;
+        let mut ctx = ProxyContext::default();
+        assert!(!plugin.request_filter(&mut session, &mut ctx).await.unwrap());
+        assert_eq!(
+            ctx.consumer.map(|consumer| consumer.inner.username.clone()),
+            Some("key-auth-jack".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_missing_or_invalid_key() {
+        let plugin = plugin("{}");
+
+        let (mut session, client) = test_session("GET / HTTP/1.1\r\n\r\n").await;
+        let mut ctx = ProxyContext::default();
+        assert!(plugin.request_filter(&mut session, &mut ctx).await.unwrap());
+        let response = test_response(session, client).await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+        assert!(response.ends_with(r#"{"message":"Missing API key in request"}"#));
+
+        let (mut session, client) = test_session("GET / HTTP/1.1\r\napikey: nope\r\n\r\n").await;
+        assert!(plugin.request_filter(&mut session, &mut ctx).await.unwrap());
+        let response = test_response(session, client).await;
+        assert!(response.starts_with("HTTP/1.1 401"));
+        assert!(response
+            .to_ascii_lowercase()
+            .contains("content-type: application/json"));
+        assert!(response.ends_with(r#"{"message":"Invalid API key in request"}"#));
+        assert!(ctx.consumer.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_hide_credentials() {
+        let plugin = plugin("hide_credentials: true");
+
+        let (mut session, _client) = test_session("GET / HTTP/1.1\r\n\r\n").await;
+        let mut upstream_request = RequestHeader::build("GET", b"/a?apikey=k&b=2", None).unwrap();
+        upstream_request.insert_header("apikey", "k").unwrap();
+        plugin
+            .upstream_request_filter(
+                &mut session,
+                &mut upstream_request,
+                &mut ProxyContext::default(),
+            )
+            .await
+            .unwrap();
+        assert!(upstream_request.headers.get("apikey").is_none());
+        assert_eq!(upstream_request.uri, "/a?b=2");
+    }
+}
@@ -1,8 +1,13 @@
+pub mod api_breaker;
+pub mod basic_auth;
 pub mod brotli;
 pub mod echo;
 pub mod grpc_web;
 pub mod gzip;
+pub mod hmac_auth;
 pub mod ip_restriction;
+pub mod jwt_auth;
+pub mod key_auth;
 pub mod limit_count;
 pub mod prometheus;
 pub mod proxy_rewrite;
@@ -12,6 +17,7 @@ use std::{collections::HashMap, sync::Arc};
 
 use async_trait::async_trait;
 use bytes::Bytes;
+use http::{header, StatusCode};
 use once_cell::sync::Lazy;
 use pingora::OkOrErr;
 use pingora_error::{Error, ErrorType::ReadError, Result};
@@ -19,8 +25,9 @@ use pingora_http::{RequestHeader, ResponseHeader};
 use pingora_proxy::Session;
 use regex::Regex;
 use serde_yaml::Value as YamlValue;
+use subtle::ConstantTimeEq;
 
-use super::{route::ProxyRoute, service::service_fetch, ProxyContext};
+use super::{consumer::ProxyConsumer, route::ProxyRoute, service::service_fetch, ProxyContext};
 
 /// Type alias for plugin initialization functions
 pub type PluginCreateFn = Arc<dyn Fn(YamlValue) -> Result<Arc<dyn ProxyPlugin>> + Send + Sync>;
@@ -47,10 +54,30 @@ static PLUGIN_BUILDER_REGISTRY: Lazy<HashMap<&'static str, PluginCreateFn>> = La
         ), // 900
         (gzip::PLUGIN_NAME, Arc::new(gzip::create_gzip_plugin)), // 995
         (brotli::PLUGIN_NAME, Arc::new(brotli::create_brotli_plugin)), // 996
+        (
+            api_breaker::PLUGIN_NAME, // 1005
+            Arc::new(api_breaker::create_api_breaker_plugin),
+        ),
         (
             proxy_rewrite::PLUGIN_NAME, // 1008
             Arc::new(proxy_rewrite::create_proxy_rewrite_plugin),
         ),
+        (
+            key_auth::PLUGIN_NAME, // 2500
+            Arc::new(key_auth::create_key_auth_plugin),
+        ),
+        (
+            jwt_auth::PLUGIN_NAME, // 2510
+            Arc::new(jwt_auth::create_jwt_auth_plugin),
+        ),
+        (
+            basic_auth::PLUGIN_NAME, // 2520
+            Arc::new(basic_auth::create_basic_auth_plugin),
+        ),
+        (
+            hmac_auth::PLUGIN_NAME, // 2530
+            Arc::new(hmac_auth::create_hmac_auth_plugin),
+        ),
         (
             ip_restriction::PLUGIN_NAME, // 3000
             Arc::new(ip_restriction::create_ip_restriction_plugin),
@@ -124,6 +151,71 @@ pub fn build_plugin_executor(route: Arc<ProxyRoute>) -> Arc<ProxyPluginExecutor>
     })
 }
 
+/// Merges the plugins of a consumer into an executor, the consumer plugins take
+/// precedence in case of naming conflicts.
+pub fn merge_consumer_plugins(
+    executor: &ProxyPluginExecutor,
+    consumer: &ProxyConsumer,
+) -> Arc<ProxyPluginExecutor> {
+    let mut plugin_map: HashMap<String, Arc<dyn ProxyPlugin>> = HashMap::new();
+
+    for plugin in consumer.plugins.iter().chain(executor.plugins.iter()) {
+        plugin_map
+            .entry(plugin.name().to_string())
+            .or_insert_with(|| plugin.clone());
+    }
+
+    let mut merged_plugins: Vec<_> = plugin_map.into_values().collect();
+    merged_plugins.sort_by_key(|b| std::cmp::Reverse(b.priority()));
+
+    Arc::new(ProxyPluginExecutor {
+        plugins: merged_plugins,
+    })
+}
+
+/// Attaches the consumer authenticated by an auth plugin to the request.
+///
+/// The plugins of the consumer are merged into the executor of the request. In the
+/// request filter phase the consumer plugins with a higher priority than the auth
+/// plugin run right after it, even if a route plugin of the same name already ran,
+/// then the merged plugins with a lower priority continue as usual.
+pub fn attach_consumer(ctx: &mut ProxyContext, consumer: Arc<ProxyConsumer>) {
+    if !consumer.plugins.is_empty() {
+        ctx.plugin = merge_consumer_plugins(&ctx.plugin, &consumer);
+    }
+    ctx.vars
+        .insert("consumer_name".to_string(), consumer.inner.username.clone());
+    ctx.consumer = Some(consumer);
+}
+
+/// Compares two credentials in constant time.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Rejects the request with a `401 Unauthorized` JSON response, `challenge` is
+/// sent as the `WWW-Authenticate` header.
+pub async fn unauthorized(
+    session: &mut Session,
+    message: &str,
+    challenge: Option<&str>,
+) -> Result<bool> {
+    let body = serde_json::json!({ "message": message }).to_string();
+
+    let mut header = ResponseHeader::build(StatusCode::UNAUTHORIZED, None)?;
+    if let Some(challenge) = challenge {
+        header.insert_header(header::WWW_AUTHENTICATE, challenge)?;
+    }
+    header.insert_header(header::CONTENT_TYPE, "application/json")?;
+    header.insert_header(header::CONTENT_LENGTH, body.len().to_string())?;
+    session
+        .write_response_header(Box::new(header), false)
+        .await?;
+    session.write_response_body(Some(body.into()), true).await?;
+
+    Ok(true)
+}
+
 #[async_trait]
 pub trait ProxyPlugin: Send + Sync {
     /// Return the name of this plugin
@@ -187,6 +279,24 @@ pub trait ProxyPlugin: Send + Sync {
         Ok(())
     }
 
+    /// Handle the request body chunks before they are sent to the upstream
+    ///
+    /// # Arguments
+    ///
+    /// * `_session` - Mutable reference to the current session
+    /// * `_body` - Mutable reference to an optional Bytes containing the body chunk
+    /// * `_end_of_stream` - Boolean indicating if this is the last chunk
+    /// * `_ctx` - Mutable reference to the plugin context
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        _body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        _ctx: &mut ProxyContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
     /// Modify the response header before it is sent to the downstream
     ///
     /// # Arguments
@@ -262,10 +372,45 @@ impl ProxyPlugin for ProxyPluginExecutor {
     }
 
     async fn request_filter(&self, session: &mut Session, ctx: &mut ProxyContext) -> Result<bool> {
-        for plugin in self.plugins.iter() {
+        // The executor of the request, replaced when an auth plugin attaches a consumer
+        let mut executor = std::ptr::eq(Arc::as_ptr(&ctx.plugin), self).then(|| ctx.plugin.clone());
+        let mut plugins = self.plugins.clone();
+        let mut index = 0;
+
+        while let Some(plugin) = plugins.get(index).cloned() {
             if plugin.request_filter(session, ctx).await? {
                 return Ok(true);
             }
+            index += 1;
+
+            // Continue with the remaining plugins of the merged executor, consumer
+            // plugins whose turn has already passed go first
+            if executor
+                .as_ref()
+                .is_some_and(|executor| !Arc::ptr_eq(executor, &ctx.plugin))
+            {
+                executor = Some(ctx.plugin.clone());
+                let mut passed: Vec<_> = ctx
+                    .consumer
+                    .iter()
+                    .flat_map(|consumer| consumer.plugins.iter())
+                    .filter(|p| p.priority() >= plugin.priority())
+                    .cloned()
+                    .collect();
+                passed.sort_by_key(|p| std::cmp::Reverse(p.priority()));
+
+                plugins = passed
+                    .into_iter()
+                    .chain(
+                        ctx.plugin
+                            .plugins
+                            .iter()
+                            .filter(|p| p.priority() < plugin.priority())
+                            .cloned(),
+                    )
+                    .collect();
+                index = 0;
+            }
         }
         Ok(false)
     }
@@ -309,6 +454,21 @@ impl ProxyPlugin for ProxyPluginExecutor {
         Ok(())
     }
 
+    async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut ProxyContext,
+    ) -> Result<()> {
+        for plugin in self.plugins.iter() {
+            plugin
+                .request_body_filter(session, body, end_of_stream, ctx)
+                .await?;
+        }
+        Ok(())
+    }
+
     fn response_body_filter(
         &self,
         session: &mut Session,
@@ -356,9 +516,169 @@ pub fn regex_template_uri(uri: &str, regex_templates: &[&str]) -> String {
     uri.to_string()
 }
 
+/// Builds a session reading a raw HTTP/1.1 request, the returned stream reads
+/// what the session writes back once the session is dropped.
+#[cfg(test)]
+pub(crate) async fn test_session(request: &str) -> (Session, tokio::io::DuplexStream) {
+    use tokio::io::AsyncWriteExt;
+
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut session = Session::new_h1(Box::new(server));
+    assert!(session.read_request().await.unwrap());
+    (session, client)
+}
+
+/// Reads the response written to a test session.
+#[cfg(test)]
+pub(crate) async fn test_response(session: Session, mut client: tokio::io::DuplexStream) -> String {
+    use tokio::io::AsyncReadExt;
+
+    drop(session);
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    response
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config;
+
+    /// Records the order request filters run in.
+    struct Recorder {
+        name: &'static str,
+        priority: i32,
+        owner: &'static str,
+    }
+
+    #[async_trait]
+    impl ProxyPlugin for Recorder {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn priority(&self) -> i32 {
+            self.priority
+        }
+
+        async fn request_filter(
+            &self,
+            _session: &mut Session,
+            ctx: &mut ProxyContext,
+        ) -> Result<bool> {
+            let order = ctx.vars.entry("order".to_string()).or_default();
+            order.push_str(&format!("{}@{},", self.name, self.owner));
+            Ok(false)
+        }
+    }
+
+    /// Attaches its consumer like an auth plugin.
+    struct Auth {
+        consumer: Arc<ProxyConsumer>,
+    }
+
+    #[async_trait]
+    impl ProxyPlugin for Auth {
+        fn name(&self) -> &str {
+            "auth"
+        }
+
+        fn priority(&self) -> i32 {
+            2500
+        }
+
+        async fn request_filter(
+            &self,
+            _session: &mut Session,
+            ctx: &mut ProxyContext,
+        ) -> Result<bool> {
+            attach_consumer(ctx, self.consumer.clone());
+            Ok(false)
+        }
+    }
+
+    fn recorder(name: &'static str, priority: i32, owner: &'static str) -> Arc<dyn ProxyPlugin> {
+        Arc::new(Recorder {
+            name,
+            priority,
+            owner,
+        })
+    }
+
+    fn test_consumer() -> Arc<ProxyConsumer> {
+        Arc::new(ProxyConsumer {
+            inner: config::Consumer {
+                username: "jack".to_string(),
+                ..Default::default()
+            },
+            plugins: vec![
+                recorder("low", 1000, "consumer"),
+                recorder("consumer-high", 2800, "consumer"),
+                recorder("consumer-low", 1500, "consumer"),
+            ],
+            jwt_key: None,
+        })
+    }
+
+    #[test]
+    fn test_merge_consumer_plugins() {
+        let executor = ProxyPluginExecutor {
+            plugins: vec![
+                recorder("high", 3000, "route"),
+                recorder("low", 1000, "route"),
+            ],
+        };
+
+        let merged = merge_consumer_plugins(&executor, &test_consumer());
+        let plugins: Vec<_> = merged
+            .plugins
+            .iter()
+            .map(|p| (p.name().to_string(), p.priority()))
+            .collect();
+        assert_eq!(
+            plugins,
+            vec![
+                ("high".to_string(), 3000),
+                ("consumer-high".to_string(), 2800),
+                ("consumer-low".to_string(), 1500),
+                ("low".to_string(), 1000),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_executor_runs_consumer_plugins() {
+        let consumer = test_consumer();
+        let executor = Arc::new(ProxyPluginExecutor {
+            plugins: vec![
+                recorder("high", 3000, "route"),
+                Arc::new(Auth {
+                    consumer: consumer.clone(),
+                }),
+                recorder("low", 1000, "route"),
+            ],
+        });
+
+        let (mut session, _client) = test_session("GET / HTTP/1.1\r\nHost: a\r\n\r\n").await;
+        let mut ctx = ProxyContext {
+            plugin: executor.clone(),
+            ..Default::default()
+        };
+        assert!(!executor
+            .request_filter(&mut session, &mut ctx)
+            .await
+            .unwrap());
+
+        // The passed consumer plugin runs right after the auth plugin and the
+        // consumer wins over the route plugin of the same name
+        assert_eq!(
+            ctx.vars["order"],
+            "high@route,consumer-high@consumer,consumer-low@consumer,low@consumer,"
+        );
+        assert_eq!(ctx.vars["consumer_name"], "jack");
+        assert_eq!(ctx.plugin.plugins.len(), 5);
+    }
 
     #[test]
     fn test_redirect_with_valid_match() {
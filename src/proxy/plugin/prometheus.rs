@@ -1,16 +1,18 @@
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use pingora_core::{Error, Result};
+use pingora_error::{ErrorType::ReadError, OrErr};
 use pingora_proxy::Session;
 use prometheus::{
     register_histogram_vec, register_int_counter_vec, register_int_gauge, HistogramOpts,
     HistogramVec, IntCounterVec, IntGauge,
 };
+use serde::{Deserialize, Serialize};
 use serde_yaml::Value as YamlValue;
 
-use crate::proxy::{get_request_host, ProxyContext};
+use crate::proxy::{get_request_host, route::ProxyRoute, ProxyContext};
 
 use super::ProxyPlugin;
 
@@ -19,6 +21,42 @@ const DEFAULT_BUCKETS: &[f64] = &[
     60000.0,
 ];
 
+// Route label keys exported as extra metric labels, set once at startup
+static ROUTE_LABELS: OnceCell<Vec<String>> = OnceCell::new();
+
+/// Sets the route label keys exported with every metric.
+///
+/// Must be called before the first request is logged, later calls are ignored.
+pub fn set_route_labels(labels: Vec<String>) {
+    if ROUTE_LABELS.set(labels).is_err() {
+        log::warn!("Prometheus route labels already initialized");
+    }
+}
+
+fn route_labels() -> &'static [String] {
+    ROUTE_LABELS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Appends the route label keys to the base label names.
+fn label_names<'a>(base: &[&'a str], route_labels: &'a [String]) -> Vec<&'a str> {
+    base.iter()
+        .copied()
+        .chain(route_labels.iter().map(String::as_str))
+        .collect()
+}
+
+/// Gets the values of the route label keys, missing labels are empty strings.
+fn route_label_values<'a>(route: Option<&'a ProxyRoute>, route_labels: &[String]) -> Vec<&'a str> {
+    route_labels
+        .iter()
+        .map(|key| {
+            route
+                .and_then(|r| r.inner.labels.get(key))
+                .map_or("", String::as_str)
+        })
+        .collect()
+}
+
 // Total number of requests
 static REQUESTS: Lazy<IntGauge> = Lazy::new(|| {
     register_int_gauge!(
@@ -33,14 +71,17 @@ static STATUS: Lazy<IntCounterVec> = Lazy::new(|| {
     register_int_counter_vec!(
         "http_status",
         "HTTP status codes per service in pingsix",
-        &[
-            "code",         // HTTP status code
-            "route",        // Route ID
-            "matched_uri",  // Matched URI
-            "matched_host", // Matched Host
-            "service",      // Service ID
-            "node",         // Node ID
-        ]
+        &label_names(
+            &[
+                "code",         // HTTP status code
+                "route",        // Route ID
+                "matched_uri",  // Matched URI
+                "matched_host", // Matched Host
+                "service",      // Service ID
+                "node",         // Node ID
+            ],
+            route_labels()
+        )
     )
     .unwrap()
 });
@@ -52,7 +93,11 @@ static LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
         "HTTP request latency in milliseconds per service in pingsix",
     )
     .buckets(DEFAULT_BUCKETS.to_vec());
-    register_histogram_vec!(opts, &["type", "route", "service", "node"]).unwrap()
+    register_histogram_vec!(
+        opts,
+        &label_names(&["type", "route", "service", "node"], route_labels())
+    )
+    .unwrap()
 });
 
 // Bandwidth counter
@@ -60,23 +105,38 @@ static BANDWIDTH: Lazy<IntCounterVec> = Lazy::new(|| {
     register_int_counter_vec!(
         "bandwidth",
         "Total bandwidth in bytes consumed per service in pingsix",
-        &[
-            "type",    // HTTP status code
-            "route",   // Route ID
-            "service", // Service ID
-            "node",    // Node ID
-        ]
+        &label_names(
+            &[
+                "type",    // HTTP status code
+                "route",   // Route ID
+                "service", // Service ID
+                "node",    // Node ID
+            ],
+            route_labels()
+        )
     )
     .unwrap()
 });
 
 pub const PLUGIN_NAME: &str = "prometheus";
 
-pub fn create_prometheus_plugin(_cfg: YamlValue) -> Result<Arc<dyn ProxyPlugin>> {
-    Ok(Arc::new(PluginPrometheus {}))
+pub fn create_prometheus_plugin(cfg: YamlValue) -> Result<Arc<dyn ProxyPlugin>> {
+    let config: PluginConfig = serde_yaml::from_value(cfg)
+        .or_err_with(ReadError, || "Invalid prometheus plugin config")?;
+    Ok(Arc::new(PluginPrometheus { config }))
+}
+
+/// Configuration for the Prometheus plugin.
+#[derive(Default, Debug, Serialize, Deserialize)]
+struct PluginConfig {
+    /// Use the route name instead of its ID in the `route` label when it is set.
+    #[serde(default)]
+    prefer_name: bool,
 }
 
-pub struct PluginPrometheus;
+pub struct PluginPrometheus {
+    config: PluginConfig,
+}
 
 #[async_trait]
 impl ProxyPlugin for PluginPrometheus {
@@ -100,7 +160,7 @@ impl ProxyPlugin for PluginPrometheus {
             .map_or("", |resp| resp.status.as_str());
 
         // Extract route information, falling back to empty string if not present
-        let route_id = route.as_ref().map_or_else(|| "", |r| r.inner.id.as_str());
+        let route_id = self.route_label(route.as_deref());
 
         // Extract URI and host, falling back to empty string or default values
         let uri = route
@@ -119,21 +179,105 @@ impl ProxyPlugin for PluginPrometheus {
         // Extract node from context variables
         let node = ctx.vars.get("upstream").map_or("", |s| s.as_str());
 
+        // Extract configured route labels, missing labels are exported as empty strings
+        let extra = route_label_values(route.as_deref(), route_labels());
+
         // Update Prometheus metrics
         STATUS
-            .with_label_values(&[code, route_id, uri, host, service, node])
+            .with_label_values(&with_extra(
+                &[code, route_id, uri, host, service, node],
+                &extra,
+            ))
             .inc();
 
         LATENCY
-            .with_label_values(&["request", route_id, service, node])
+            .with_label_values(&with_extra(&["request", route_id, service, node], &extra))
             .observe(ctx.request_start.elapsed().as_millis() as f64);
 
         BANDWIDTH
-            .with_label_values(&["ingress", route_id, service, node])
+            .with_label_values(&with_extra(&["ingress", route_id, service, node], &extra))
             .inc_by(session.body_bytes_read() as _);
 
         BANDWIDTH
-            .with_label_values(&["egress", route_id, service, node])
+            .with_label_values(&with_extra(&["egress", route_id, service, node], &extra))
             .inc_by(session.body_bytes_sent() as _);
     }
 }
+
+impl PluginPrometheus {
+    /// Gets the `route` label, the route name when preferred and set, else its ID.
+    fn route_label<'a>(&self, route: Option<&'a ProxyRoute>) -> &'a str {
+        route.map_or("", |r| match &r.inner.name {
+            Some(name) if self.config.prefer_name => name.as_str(),
+            _ => r.inner.id.as_str(),
+        })
+    }
+}
+
+/// Appends the route label values to the base label values.
+fn with_extra<'a>(base: &[&'a str], extra: &[&'a str]) -> Vec<&'a str> {
+    base.iter().chain(extra).copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    fn route() -> ProxyRoute {
+        let route: config::Route = serde_yaml::from_str(
+            r#"
+id: route-1
+name: users
+uri: /users
+labels:
+  team: payments
+"#,
+        )
+        .unwrap();
+        ProxyRoute::from(route)
+    }
+
+    #[test]
+    fn test_label_names() {
+        let route_labels = vec!["team".to_string(), "env".to_string()];
+        assert_eq!(
+            label_names(&["code", "route"], &route_labels),
+            ["code", "route", "team", "env"]
+        );
+        assert_eq!(label_names(&["code", "route"], &[]), ["code", "route"]);
+    }
+
+    #[test]
+    fn test_route_label_values() {
+        let route = route();
+        let route_labels = vec!["team".to_string(), "env".to_string()];
+
+        // Missing route labels are exported as empty values
+        assert_eq!(
+            route_label_values(Some(&route), &route_labels),
+            ["payments", ""]
+        );
+        assert_eq!(route_label_values(None, &route_labels), ["", ""]);
+        assert!(route_label_values(Some(&route), &[]).is_empty());
+    }
+
+    #[test]
+    fn test_route_label() {
+        let mut route = route();
+        let by_id = PluginPrometheus {
+            config: PluginConfig { prefer_name: false },
+        };
+        let by_name = PluginPrometheus {
+            config: PluginConfig { prefer_name: true },
+        };
+
+        assert_eq!(by_id.route_label(Some(&route)), "route-1");
+        assert_eq!(by_name.route_label(Some(&route)), "users");
+        assert_eq!(by_name.route_label(None), "");
+
+        // Routes without a name fall back to their ID
+        route.inner.name = None;
+        assert_eq!(by_name.route_label(Some(&route)), "route-1");
+    }
+}
@@ -1,25 +1,29 @@
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::Duration;
 use std::{collections::BTreeMap, sync::RwLock};
 
 use arc_swap::ArcSwap;
 use log::debug;
-use matchit::{InsertError, Router as MatchRouter};
-use once_cell::sync::Lazy;
+use matchit::{InsertError, Params, Router as MatchRouter};
+use once_cell::sync::{Lazy, OnceCell};
 use pingora_core::upstreams::peer::HttpPeer;
-use pingora_error::{Error, Result};
+use pingora_error::{Error, ErrorType::ReadError, OrErr, Result};
 use pingora_proxy::Session;
+use regex::Regex;
 
-use crate::config;
+use crate::config::{self, RouteMatchMode};
 
 use super::{
-    get_request_host,
+    apply_timeout,
+    expr::{eval_vars, parse_vars, VarExpr},
+    get_request_host, handle_vars,
     plugin::build_plugin,
     plugin::ProxyPlugin,
     service::service_fetch,
     upstream::{upstream_fetch, ProxyUpstream},
-    Identifiable, MapOperations,
+    Identifiable, MapOperations, ProxyContext,
 };
 
 /// Proxy route.
@@ -29,6 +33,8 @@ pub struct ProxyRoute {
     pub inner: config::Route,
     pub upstream: Option<Arc<ProxyUpstream>>,
     pub plugins: Vec<Arc<dyn ProxyPlugin>>,
+    pub vars: Vec<VarExpr>,
+    pub uri_regex: Option<Regex>,
 }
 
 impl From<config::Route> for ProxyRoute {
@@ -38,6 +44,8 @@ impl From<config::Route> for ProxyRoute {
             inner: value,
             upstream: None,
             plugins: Vec::new(),
+            vars: Vec::new(),
+            uri_regex: None,
         }
     }
 }
@@ -72,6 +80,15 @@ impl ProxyRoute {
             proxy_route.plugins.push(plugin);
         }
 
+        // 编译 vars 表达式和 uri_regex
+        proxy_route.vars = parse_vars(&route.vars)?;
+        proxy_route.uri_regex = route
+            .uri_regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .or_err(ReadError, "Invalid uri_regex")?;
+
         Ok(proxy_route)
     }
 
@@ -110,15 +127,49 @@ impl ProxyRoute {
         }
     }
 
+    /// Checks whether the request method is accepted by the route.
+    fn match_method(&self, method: &str) -> bool {
+        self.inner.methods.is_empty() || self.inner.methods.iter().any(|m| m.to_string() == method)
+    }
+
+    /// Checks whether the client address is accepted by the route.
+    fn match_remote_addr(&self, client_ip: Option<IpAddr>) -> bool {
+        let remote_addrs = match &self.inner.remote_addr {
+            Some(addr) => std::slice::from_ref(addr),
+            None => self.inner.remote_addrs.as_slice(),
+        };
+        if remote_addrs.is_empty() {
+            return true;
+        }
+
+        client_ip.is_some_and(|ip| {
+            let ip = ip.to_canonical();
+            remote_addrs.iter().any(|network| network.contains(ip))
+        })
+    }
+
+    /// Checks whether the request satisfies the route `vars` expressions.
+    fn match_vars(&self, session: &Session) -> bool {
+        eval_vars(&self.vars, |key| handle_vars(session, key))
+    }
+
     /// Selects an HTTP peer for a given session.
-    pub fn select_http_peer<'a>(&'a self, session: &'a mut Session) -> Result<Box<HttpPeer>> {
+    ///
+    /// The selected backend is tracked as in flight in `ctx` until the request ends.
+    pub fn select_http_peer<'a>(
+        &'a self,
+        session: &'a mut Session,
+        ctx: &mut ProxyContext,
+    ) -> Result<Box<HttpPeer>> {
         let upstream = self
             .resolve_upstream()
             .ok_or_else(|| Error::new_str("Failed to retrieve upstream configuration for route"))?;
 
         let mut backend = upstream
-            .select_backend(session)
+            .select_backend(session, &ctx.tried_backends)
             .ok_or_else(|| Error::new_str("Unable to determine backend for the request"))?;
+        ctx.inflight = Some(upstream.acquire(&backend));
+        ctx.tried_backends.push(backend.addr.clone());
 
         backend
             .ext
@@ -132,145 +183,367 @@ impl ProxyRoute {
 
     /// Sets the timeout for an `HttpPeer` based on the route configuration.
     fn set_timeout(&self, p: &mut HttpPeer) {
-        if let Some(config::Timeout {
-            connect,
-            read,
-            send,
-        }) = self.inner.timeout
-        {
-            p.options.connection_timeout = Some(Duration::from_secs(connect));
-            p.options.read_timeout = Some(Duration::from_secs(read));
-            p.options.write_timeout = Some(Duration::from_secs(send));
+        if let Some(timeout) = self.inner.timeout.as_ref() {
+            apply_timeout(timeout, p);
         }
     }
 }
 
 #[derive(Default)]
 pub struct MatchEntry {
-    /// Router for non-host URI matching
-    non_host_uri: MatchRouter<Vec<Arc<ProxyRoute>>>,
-    /// Router for host URI matching
-    host_uris: MatchRouter<MatchRouter<Vec<Arc<ProxyRoute>>>>,
+    /// How candidates from different uri patterns and hosts are ranked
+    mode: RouteMatchMode,
+    /// Matcher for non-host URI matching
+    non_host_uri: UriMatcher,
+    /// Matchers for exact host URI matching, keyed by lowercase host
+    host_uris: HashMap<String, UriMatcher>,
+    /// Matchers for wildcard host URI matching, keyed by host suffix (`*.example.com` -> `.example.com`)
+    wildcard_host_uris: HashMap<String, UriMatcher>,
 }
 
 impl MatchEntry {
+    /// Creates an empty match entry using the given match mode.
+    pub fn new(mode: RouteMatchMode) -> Self {
+        Self {
+            mode,
+            ..Default::default()
+        }
+    }
+
     /// Inserts a route into the match entry.
     pub fn insert_route(&mut self, proxy_route: Arc<ProxyRoute>) -> Result<(), InsertError> {
         let hosts = proxy_route.get_hosts();
-        let uris = proxy_route.inner.get_uris();
+        let mode = self.mode;
 
         if hosts.is_empty() {
             // Insert for non-host URIs
-            Self::insert_route_for_uri(&mut self.non_host_uri, &uris, proxy_route)?;
+            self.non_host_uri.insert_route(proxy_route, mode)?;
         } else {
             // Insert for host URIs
             for host in hosts.iter() {
-                let reversed_host = host.chars().rev().collect::<String>();
-
-                if self.host_uris.at(reversed_host.as_str()).is_err() {
-                    let mut inner = MatchRouter::new();
-                    for uri in uris.iter() {
-                        inner.insert(uri, vec![proxy_route.clone()])?;
-                    }
-                    self.host_uris.insert(reversed_host, inner)?;
-                } else {
-                    let inner = self.host_uris.at_mut(reversed_host.as_str()).unwrap().value;
-                    Self::insert_route_for_uri(inner, &uris, proxy_route.clone())?;
-                }
+                let host = host.to_lowercase();
+
+                let uri_matcher = match host.strip_prefix('*') {
+                    Some(suffix) => self
+                        .wildcard_host_uris
+                        .entry(suffix.to_string())
+                        .or_default(),
+                    None => self.host_uris.entry(host).or_default(),
+                };
+                uri_matcher.insert_route(proxy_route.clone(), mode)?;
             }
         }
 
         Ok(())
     }
 
-    /// Inserts a route for a given URI.
-    fn insert_route_for_uri(
-        match_router: &mut MatchRouter<Vec<Arc<ProxyRoute>>>,
-        uris: &[String],
-        proxy_route: Arc<ProxyRoute>,
-    ) -> Result<(), InsertError> {
-        for uri in uris.iter() {
-            if match_router.at(uri).is_err() {
-                match_router.insert(uri, vec![proxy_route.clone()])?;
-            } else {
-                let routes = match_router.at_mut(uri).unwrap();
-                routes.value.push(proxy_route.clone());
-                // Sort by priority
-                routes
-                    .value
-                    .sort_by(|a, b| b.inner.priority.cmp(&a.inner.priority));
-            }
-        }
-        Ok(())
-    }
-
     /// Matches a request to a route.
     pub fn match_request(
         &self,
-        session: &mut Session,
+        session: &Session,
     ) -> Option<(BTreeMap<String, String>, Arc<ProxyRoute>)> {
         let host = get_request_host(session.req_header());
         let uri = session.req_header().uri.path();
         let method = session.req_header().method.as_str();
+        let client_ip = session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|addr| addr.ip());
 
         log::debug!(
-            "match request: host={:?}, uri={:?}, method={:?}",
+            "match request: host={:?}, uri={:?}, method={:?}, client_ip={:?}",
             host,
             uri,
-            method
+            method,
+            client_ip
         );
 
-        // Attempt to match using host_uris if a valid host is provided
-        if let Some(reversed_host) = host
-            .filter(|h| !h.is_empty())
-            .map(|h| h.chars().rev().collect::<String>())
-        {
-            if let Ok(v) = self.host_uris.at(&reversed_host) {
-                if let Some(result) = Self::match_uri(v.value, uri, method) {
-                    return Some(result);
+        self.match_route(host, uri, method, client_ip, |route| {
+            route.match_vars(session)
+        })
+    }
+
+    /// Matches the request attributes to a route.
+    ///
+    /// `filter` is evaluated for every route whose uri, host, method and remote
+    /// address match, and allows request dependent conditions (such as `vars`) to
+    /// reject a route so the next candidate can be tried.
+    fn match_route<F>(
+        &self,
+        host: Option<&str>,
+        uri: &str,
+        method: &str,
+        client_ip: Option<IpAddr>,
+        filter: F,
+    ) -> Option<(BTreeMap<String, String>, Arc<ProxyRoute>)>
+    where
+        F: Fn(&ProxyRoute) -> bool,
+    {
+        let uri_matchers = self.host_uri_matchers(host);
+        let accept = |route: &ProxyRoute| {
+            route.match_method(method) && route.match_remote_addr(client_ip) && filter(route)
+        };
+
+        match self.mode {
+            // The first host tier with an acceptable route wins
+            RouteMatchMode::Specificity => uri_matchers.into_iter().find_map(|uri_matcher| {
+                let mut candidates = Vec::new();
+                uri_matcher.collect_candidates(uri, 0, &mut candidates);
+                select_candidate(candidates, uri, &accept)
+            }),
+            // All host tiers compete, the highest priority wins
+            RouteMatchMode::Priority => {
+                let mut candidates = Vec::new();
+                for (tier, uri_matcher) in uri_matchers.into_iter().enumerate() {
+                    uri_matcher.collect_candidates(uri, tier, &mut candidates);
                 }
+                select_candidate(candidates, uri, &accept)
+            }
+        }
+    }
+
+    /// Returns the uri matchers applicable to the host, from the most specific
+    /// to the least specific one.
+    fn host_uri_matchers(&self, host: Option<&str>) -> Vec<&UriMatcher> {
+        let mut uri_matchers = Vec::new();
+
+        if let Some(host) = host.filter(|h| !h.is_empty()).map(|h| h.to_lowercase()) {
+            // Exact hosts take precedence over wildcard hosts
+            uri_matchers.extend(self.host_uris.get(&host));
+
+            // Wildcard hosts, from the longest suffix to the shortest one
+            for (i, _) in host.match_indices('.') {
+                uri_matchers.extend(self.wildcard_host_uris.get(&host[i..]));
             }
         }
 
         // Fall back to non-host URI matching
-        Self::match_uri(&self.non_host_uri, uri, method)
+        uri_matchers.push(&self.non_host_uri);
+        uri_matchers
     }
+}
 
-    /// Matches a URI to a route.
-    fn match_uri(
-        match_router: &MatchRouter<Vec<Arc<ProxyRoute>>>,
-        uri: &str,
-        method: &str,
-    ) -> Option<(BTreeMap<String, String>, Arc<ProxyRoute>)> {
-        if let Ok(v) = match_router.at(uri) {
-            let params: BTreeMap<String, String> = v
-                .params
-                .iter()
-                .map(|(k, v)| (k.to_string(), v.to_string()))
-                .collect();
+/// URI matcher for the routes of a single host.
+///
+/// Besides matchit patterns, it supports APISIX style prefix uris (`/api/*`) and
+/// `uri_regex` routes.
+#[derive(Default)]
+struct UriMatcher {
+    /// Routers for matchit URI patterns.
+    ///
+    /// In specificity mode all patterns share one router, so only the most
+    /// specific pattern matches. In priority mode every pattern gets its own
+    /// router so that all matching patterns become candidates.
+    routers: Vec<MatchRouter<Vec<Arc<ProxyRoute>>>>,
+    /// Index into `routers` by pattern, only used in priority mode
+    pattern_routers: HashMap<String, usize>,
+    /// Prefix routes, keyed by the uri without its trailing `*`
+    prefixes: Vec<(String, Arc<ProxyRoute>)>,
+    /// Routes matched by `uri_regex`
+    regexes: Vec<Arc<ProxyRoute>>,
+}
+
+/// How a route matched the request URI.
+///
+/// Ordered by specificity: patterns first, then regexes, which match the whole
+/// uri, then longer prefixes.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum UriMatchKind {
+    Pattern,
+    Regex,
+    Prefix(Reverse<usize>),
+}
 
-            for route in v.value.iter() {
-                if route.inner.methods.is_empty() {
-                    return Some((params, route.clone()));
+/// A route whose URI matched the request.
+struct Candidate<'a> {
+    route: &'a Arc<ProxyRoute>,
+    /// Host tier the route was found in, lower is more specific
+    tier: usize,
+    kind: UriMatchKind,
+    /// Captured matchit params for pattern matches
+    params: Option<Params<'a, 'a>>,
+}
+
+impl UriMatcher {
+    /// Inserts a route for all of its URIs.
+    fn insert_route(
+        &mut self,
+        proxy_route: Arc<ProxyRoute>,
+        mode: RouteMatchMode,
+    ) -> Result<(), InsertError> {
+        for uri in proxy_route.inner.get_uris().iter() {
+            match uri.strip_suffix('*') {
+                Some(prefix) if !uri.contains('{') => {
+                    self.prefixes
+                        .push((prefix.to_string(), proxy_route.clone()));
+                }
+                _ => {
+                    let match_router = self.pattern_router(uri, mode);
+                    Self::insert_route_for_uri(match_router, uri, proxy_route.clone())?
                 }
+            }
+        }
 
-                // Match method
-                if route
-                    .inner
-                    .methods
-                    .iter()
-                    .map(|method| method.to_string())
-                    .collect::<Vec<String>>()
-                    .contains(&method.to_string())
-                {
-                    return Some((params, route.clone()));
+        if proxy_route.uri_regex.is_some() {
+            self.regexes.push(proxy_route);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the router a URI pattern should be inserted into.
+    fn pattern_router(
+        &mut self,
+        uri: &str,
+        mode: RouteMatchMode,
+    ) -> &mut MatchRouter<Vec<Arc<ProxyRoute>>> {
+        let index = match mode {
+            RouteMatchMode::Specificity => 0,
+            RouteMatchMode::Priority => match self.pattern_routers.get(uri) {
+                Some(index) => *index,
+                None => {
+                    self.pattern_routers
+                        .insert(uri.to_string(), self.routers.len());
+                    self.routers.len()
                 }
+            },
+        };
+
+        if index == self.routers.len() {
+            self.routers.push(MatchRouter::new());
+        }
+        &mut self.routers[index]
+    }
+
+    /// Inserts a route for a given URI.
+    fn insert_route_for_uri(
+        match_router: &mut MatchRouter<Vec<Arc<ProxyRoute>>>,
+        uri: &str,
+        proxy_route: Arc<ProxyRoute>,
+    ) -> Result<(), InsertError> {
+        if match_router.at(uri).is_err() {
+            match_router.insert(uri, vec![proxy_route])?;
+        } else {
+            let routes = match_router.at_mut(uri).unwrap();
+            routes.value.push(proxy_route);
+            // Sort by priority, then by id to keep the order deterministic
+            routes.value.sort_by(|a, b| {
+                b.inner
+                    .priority
+                    .cmp(&a.inner.priority)
+                    .then_with(|| a.inner.id.cmp(&b.inner.id))
+            });
+        }
+        Ok(())
+    }
+
+    /// Collects the routes whose URI matches the request.
+    fn collect_candidates<'a>(
+        &'a self,
+        uri: &'a str,
+        tier: usize,
+        candidates: &mut Vec<Candidate<'a>>,
+    ) {
+        for router in self.routers.iter() {
+            if let Ok(matched) = router.at(uri) {
+                candidates.extend(matched.value.iter().map(|route| Candidate {
+                    route,
+                    tier,
+                    kind: UriMatchKind::Pattern,
+                    params: Some(matched.params.clone()),
+                }));
             }
         }
-        None
+
+        candidates.extend(
+            self.prefixes
+                .iter()
+                .filter(|(prefix, _)| uri.starts_with(prefix.as_str()))
+                .map(|(prefix, route)| Candidate {
+                    route,
+                    tier,
+                    kind: UriMatchKind::Prefix(Reverse(prefix.len())),
+                    params: None,
+                }),
+        );
+
+        candidates.extend(
+            self.regexes
+                .iter()
+                .filter(|route| route.uri_regex.as_ref().is_some_and(|re| re.is_match(uri)))
+                .map(|route| Candidate {
+                    route,
+                    tier,
+                    kind: UriMatchKind::Regex,
+                    params: None,
+                }),
+        );
     }
 }
 
+/// Selects the first acceptable candidate.
+///
+/// Candidates are ordered by route priority first, then by host tier and by how
+/// specifically they matched the URI for equal priorities.
+fn select_candidate<F>(
+    mut candidates: Vec<Candidate<'_>>,
+    uri: &str,
+    accept: &F,
+) -> Option<(BTreeMap<String, String>, Arc<ProxyRoute>)>
+where
+    F: Fn(&ProxyRoute) -> bool,
+{
+    candidates.sort_by(|a, b| {
+        b.route
+            .inner
+            .priority
+            .cmp(&a.route.inner.priority)
+            .then_with(|| a.tier.cmp(&b.tier))
+            .then_with(|| a.kind.cmp(&b.kind))
+            .then_with(|| a.route.inner.id.cmp(&b.route.inner.id))
+    });
+
+    let candidate = candidates
+        .into_iter()
+        .find(|candidate| accept(candidate.route))?;
+
+    let params: BTreeMap<String, String> = match candidate.kind {
+        UriMatchKind::Pattern => candidate
+            .params
+            .map(|params| {
+                params
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        UriMatchKind::Prefix(_) => BTreeMap::new(),
+        UriMatchKind::Regex => candidate
+            .route
+            .uri_regex
+            .as_ref()
+            .map(|re| regex_params(re, uri))
+            .unwrap_or_default(),
+    };
+
+    Some((params, candidate.route.clone()))
+}
+
+/// Collects the named captures of a regex match.
+fn regex_params(re: &Regex, uri: &str) -> BTreeMap<String, String> {
+    re.captures(uri)
+        .map(|caps| {
+            re.capture_names()
+                .flatten()
+                .filter_map(|name| {
+                    caps.name(name)
+                        .map(|m| (name.to_string(), m.as_str().to_string()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 /// Global map to store global rules, initialized lazily.
 pub static ROUTE_MAP: Lazy<RwLock<HashMap<String, Arc<ProxyRoute>>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
@@ -281,11 +554,38 @@ pub fn global_match_fetch() -> Arc<MatchEntry> {
     GLOBAL_MATCH.load().clone()
 }
 
+/// Route match mode, set once at startup.
+static ROUTE_MATCH_MODE: OnceCell<RouteMatchMode> = OnceCell::new();
+
+/// Sets the route match mode used by `reload_global_match`.
+pub fn set_route_match_mode(mode: RouteMatchMode) {
+    if ROUTE_MATCH_MODE.set(mode).is_err() {
+        log::warn!("Route match mode already initialized");
+    }
+}
+
 pub fn reload_global_match() {
-    let mut matcher = MatchEntry::default();
+    let mode = ROUTE_MATCH_MODE.get().copied().unwrap_or_default();
+    let mut matcher = MatchEntry::new(mode);
 
     let routes = ROUTE_MAP.read().unwrap();
     for route in routes.values() {
+        if !route.inner.is_enabled() {
+            debug!("Skipping disabled route: {}", route.inner.id);
+            continue;
+        }
+
+        let service_disabled = route
+            .inner
+            .service_id
+            .as_deref()
+            .and_then(service_fetch)
+            .is_some_and(|service| !service.inner.is_enabled());
+        if service_disabled {
+            debug!("Skipping route {} of disabled service", route.inner.id);
+            continue;
+        }
+
         debug!("Inserting route: {}", route.inner.id);
         matcher.insert_route(route.clone()).unwrap();
     }
@@ -330,3 +630,354 @@ pub fn route_fetch(id: &str) -> Option<Arc<ProxyRoute>> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_entry(routes: &str) -> MatchEntry {
+        build_entry_with_mode(routes, RouteMatchMode::default())
+    }
+
+    fn build_entry_with_mode(routes: &str, mode: RouteMatchMode) -> MatchEntry {
+        let routes: Vec<config::Route> = serde_yaml::from_str(routes).unwrap();
+        let mut entry = MatchEntry::new(mode);
+        for route in routes {
+            let proxy_route = ProxyRoute::new_with_upstream_and_plugins(route, false).unwrap();
+            entry.insert_route(Arc::new(proxy_route)).unwrap();
+        }
+        entry
+    }
+
+    fn match_id(entry: &MatchEntry, host: Option<&str>, uri: &str, method: &str) -> Option<String> {
+        entry
+            .match_route(host, uri, method, None, |_| true)
+            .map(|(_, route)| route.inner.id.clone())
+    }
+
+    #[test]
+    fn test_match_wildcard_host() {
+        let entry = build_entry(
+            r#"
+- id: exact
+  uri: /
+  host: api.example.com
+  upstream_id: "1"
+- id: wildcard
+  uri: /
+  host: "*.example.com"
+  upstream_id: "1"
+- id: nested-wildcard
+  uri: /
+  host: "*.v1.example.com"
+  upstream_id: "1"
+- id: any
+  uri: /
+  upstream_id: "1"
+"#,
+        );
+
+        let cases = [
+            (Some("api.example.com"), "exact"),
+            (Some("API.Example.com"), "exact"),
+            (Some("www.example.com"), "wildcard"),
+            (Some("a.b.example.com"), "wildcard"),
+            (Some("foo.v1.example.com"), "nested-wildcard"),
+            (Some("example.com"), "any"),
+            (Some("example.org"), "any"),
+            (None, "any"),
+        ];
+
+        for (host, expected) in cases {
+            assert_eq!(
+                match_id(&entry, host, "/", "GET").as_deref(),
+                Some(expected),
+                "host: {:?}",
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn test_match_host_fallback_order() {
+        let entry = build_entry(
+            r#"
+- id: exact-post
+  uri: /users
+  host: api.example.com
+  methods: [POST]
+  upstream_id: "1"
+- id: wildcard
+  uri: /users
+  host: "*.example.com"
+  upstream_id: "1"
+- id: any
+  uri: /orders
+  upstream_id: "1"
+"#,
+        );
+
+        // Exact host route is skipped by method, wildcard host route is the next candidate
+        assert_eq!(
+            match_id(&entry, Some("api.example.com"), "/users", "GET").as_deref(),
+            Some("wildcard")
+        );
+        assert_eq!(
+            match_id(&entry, Some("api.example.com"), "/users", "POST").as_deref(),
+            Some("exact-post")
+        );
+        // No host-specific uri matches, falls back to host-less routes
+        assert_eq!(
+            match_id(&entry, Some("api.example.com"), "/orders", "GET").as_deref(),
+            Some("any")
+        );
+        assert_eq!(match_id(&entry, Some("example.org"), "/users", "GET"), None);
+    }
+
+    #[test]
+    fn test_match_prefix_and_regex_uri() {
+        let entry = build_entry(
+            r#"
+- id: pattern
+  uri: /api/users/{id}
+  upstream_id: "1"
+- id: prefix
+  uri: /api/*
+  upstream_id: "1"
+- id: longer-prefix
+  uri: /api/orders/*
+  upstream_id: "1"
+- id: regex
+  uri_regex: ^/files/(?P<name>[^/]+)\.(?P<ext>[a-z]+)$
+  upstream_id: "1"
+- id: catch-all
+  uri: /*
+  upstream_id: "1"
+"#,
+        );
+
+        let cases = [
+            ("/api/users/1", "pattern"),
+            ("/api/users", "prefix"),
+            ("/api/", "prefix"),
+            ("/api/orders/1/items", "longer-prefix"),
+            ("/files/report.pdf", "regex"),
+            ("/files/report", "catch-all"),
+            ("/api", "catch-all"),
+        ];
+
+        for (uri, expected) in cases {
+            assert_eq!(
+                match_id(&entry, None, uri, "GET").as_deref(),
+                Some(expected),
+                "uri: {}",
+                uri
+            );
+        }
+
+        let (params, _) = entry
+            .match_route(None, "/files/report.pdf", "GET", None, |_| true)
+            .unwrap();
+        assert_eq!(params.get("name").map(|s| s.as_str()), Some("report"));
+        assert_eq!(params.get("ext").map(|s| s.as_str()), Some("pdf"));
+
+        let (params, _) = entry
+            .match_route(None, "/api/users/1", "GET", None, |_| true)
+            .unwrap();
+        assert_eq!(params.get("id").map(|s| s.as_str()), Some("1"));
+    }
+
+    #[test]
+    fn test_match_prefix_priority() {
+        let entry = build_entry(
+            r#"
+- id: pattern
+  uri: /api/users/{id}
+  upstream_id: "1"
+- id: prefix
+  uri: /api/*
+  priority: 10
+  upstream_id: "1"
+"#,
+        );
+
+        // Higher priority prefix routes win over more specific patterns
+        assert_eq!(
+            match_id(&entry, None, "/api/users/1", "GET").as_deref(),
+            Some("prefix")
+        );
+
+        // Lower priority candidates are used when higher ones are rejected
+        let matched = entry
+            .match_route(None, "/api/users/1", "GET", None, |route| {
+                route.inner.id != "prefix"
+            })
+            .map(|(_, route)| route.inner.id.clone());
+        assert_eq!(matched.as_deref(), Some("pattern"));
+    }
+
+    #[test]
+    fn test_match_remote_addrs() {
+        let entry = build_entry(
+            r#"
+- id: internal
+  uri: /
+  remote_addrs: ["10.0.0.0/8", "192.168.1.1"]
+  priority: 10
+  upstream_id: "1"
+- id: ipv6
+  uri: /
+  remote_addr: "2001:db8::/32"
+  priority: 5
+  upstream_id: "1"
+- id: public
+  uri: /
+  upstream_id: "1"
+"#,
+        );
+
+        let cases = [
+            (Some("10.1.2.3"), "internal"),
+            (Some("192.168.1.1"), "internal"),
+            (Some("::ffff:10.1.2.3"), "internal"),
+            (Some("2001:db8::1"), "ipv6"),
+            (Some("192.168.1.2"), "public"),
+            (None, "public"),
+        ];
+
+        for (client_ip, expected) in cases {
+            let client_ip = client_ip.map(|ip| ip.parse::<IpAddr>().unwrap());
+            let matched = entry
+                .match_route(None, "/", "GET", client_ip, |_| true)
+                .map(|(_, route)| route.inner.id.clone());
+            assert_eq!(
+                matched.as_deref(),
+                Some(expected),
+                "client_ip: {:?}",
+                client_ip
+            );
+        }
+    }
+
+    #[test]
+    fn test_match_same_uri_priority() {
+        let entry = build_entry(
+            r#"
+- id: b
+  uri: /
+  upstream_id: "1"
+- id: a
+  uri: /
+  upstream_id: "1"
+- id: high
+  uri: /
+  priority: 10
+  upstream_id: "1"
+"#,
+        );
+
+        assert_eq!(match_id(&entry, None, "/", "GET").as_deref(), Some("high"));
+
+        // Routes rejected by the filter fall through to the next one in order
+        let matched = entry
+            .match_route(None, "/", "GET", None, |route| route.inner.id != "high")
+            .map(|(_, route)| route.inner.id.clone());
+        assert_eq!(matched.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn test_match_priority_mode() {
+        let routes = r#"
+- id: param
+  uri: /users/{id}
+  upstream_id: "1"
+- id: catch-all
+  uri: /users/{*rest}
+  priority: 10
+  upstream_id: "1"
+- id: catch-all-post
+  uri: /users/{*rest}
+  methods: [POST]
+  priority: 20
+  upstream_id: "1"
+- id: host
+  uri: /orders
+  host: api.example.com
+  upstream_id: "1"
+- id: any-host
+  uri: /orders
+  priority: 5
+  upstream_id: "1"
+"#;
+
+        let entry = build_entry_with_mode(routes, RouteMatchMode::Priority);
+        let cases = [
+            (None, "/users/1", "GET", "catch-all"),
+            (None, "/users/1", "POST", "catch-all-post"),
+            (None, "/users/1/orders", "GET", "catch-all"),
+            (Some("api.example.com"), "/orders", "GET", "any-host"),
+        ];
+        for (host, uri, method, expected) in cases {
+            assert_eq!(
+                match_id(&entry, host, uri, method).as_deref(),
+                Some(expected),
+                "{} {}",
+                method,
+                uri
+            );
+        }
+
+        let (params, route) = entry
+            .match_route(None, "/users/1", "GET", None, |route| {
+                route.inner.id != "catch-all"
+            })
+            .unwrap();
+        assert_eq!(route.inner.id, "param");
+        assert_eq!(params.get("id").map(String::as_str), Some("1"));
+
+        // Specificity mode keeps the most specific pattern and host
+        let routes = r#"
+- id: param
+  uri: /users/{id}
+  upstream_id: "1"
+- id: catch-all
+  uri: /{*rest}
+  priority: 10
+  upstream_id: "1"
+- id: host
+  uri: /orders
+  host: api.example.com
+  upstream_id: "1"
+- id: any-host
+  uri: /orders
+  priority: 15
+  upstream_id: "1"
+"#;
+        let cases = [
+            (RouteMatchMode::Specificity, None, "/users/1", "param"),
+            (
+                RouteMatchMode::Specificity,
+                Some("api.example.com"),
+                "/orders",
+                "host",
+            ),
+            (RouteMatchMode::Priority, None, "/users/1", "catch-all"),
+            (
+                RouteMatchMode::Priority,
+                Some("api.example.com"),
+                "/orders",
+                "any-host",
+            ),
+        ];
+        for (mode, host, uri, expected) in cases {
+            let entry = build_entry_with_mode(routes, mode);
+            assert_eq!(
+                match_id(&entry, host, uri, "GET").as_deref(),
+                Some(expected),
+                "{:?} {}",
+                mode,
+                uri
+            );
+        }
+    }
+}
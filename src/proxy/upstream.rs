@@ -1,14 +1,21 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
-    time::Duration,
+    collections::{BTreeSet, HashMap, HashSet},
+    os::unix::io::RawFd,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
+use async_trait::async_trait;
 use http::Uri;
 use log::info;
 use once_cell::sync::Lazy;
 use pingora::services::background::background_service;
-use pingora_core::{services::Service, upstreams::peer::HttpPeer};
+use pingora_core::{
+    protocols::l4::socket::SocketAddr, services::Service, upstreams::peer::HttpPeer,
+};
 use pingora_error::{Error, Result};
 use pingora_http::{RequestHeader, ResponseHeader};
 use pingora_load_balancing::{
@@ -20,11 +27,16 @@ use pingora_load_balancing::{
 };
 use pingora_proxy::Session;
 use pingora_runtime::Runtime;
+use rand::Rng;
 use tokio::sync::watch;
 
 use crate::config;
 
-use super::{discovery::HybridDiscovery, request_selector_key, Identifiable, MapOperations};
+use super::{
+    apply_timeout,
+    discovery::{backend_priority, HybridDiscovery},
+    request_selector_key, Identifiable, MapOperations,
+};
 
 /// Proxy load balancer.
 ///
@@ -32,6 +44,16 @@ use super::{discovery::HybridDiscovery, request_selector_key, Identifiable, MapO
 pub struct ProxyUpstream {
     pub inner: config::Upstream,
     lb: SelectionLB,
+    /// Runtime statistics of the backends
+    stats: Arc<UpstreamStats>,
+    /// Passive health check configuration
+    passive: Option<Arc<config::PassiveCheck>>,
+    /// Whether the nodes are split into priority groups
+    has_priorities: bool,
+    /// Connections to the upstream, tracked when a keepalive pool is configured
+    connections: Arc<ConnectionTracker>,
+    /// Rotating start offset used to break ties between equally loaded backends
+    cursor: AtomicUsize,
 
     runtime: Option<Runtime>,
     watch: Option<watch::Sender<bool>>,
@@ -42,9 +64,23 @@ impl TryFrom<config::Upstream> for ProxyUpstream {
 
     /// Creates a new `ProxyLB` instance from an `Upstream` configuration.
     fn try_from(value: config::Upstream) -> Result<Self> {
+        let stats = Arc::new(UpstreamStats::default());
+        let passive = value
+            .checks
+            .as_ref()
+            .and_then(|checks| checks.passive.clone())
+            .map(Arc::new);
+        // Discovered nodes may come with priorities
+        let has_priorities = value.nodes.has_priorities() || value.discovery_type.is_some();
+
         Ok(Self {
             inner: value.clone(),
-            lb: SelectionLB::try_from(value)?,
+            lb: SelectionLB::new(value, &stats)?,
+            stats,
+            passive,
+            has_priorities,
+            connections: Arc::new(ConnectionTracker::default()),
+            cursor: AtomicUsize::new(0),
             runtime: None,
             watch: None,
         })
@@ -101,15 +137,20 @@ impl ProxyUpstream {
     }
 
     /// Selects a backend server for a given session.
-    pub fn select_backend<'a>(&'a self, session: &'a mut Session) -> Option<Backend> {
+    ///
+    /// Backends in `exclude`, i.e. the ones tried by previous attempts of the
+    /// request, are only selected again when no other backend is available.
+    pub fn select_backend<'a>(
+        &'a self,
+        session: &'a mut Session,
+        exclude: &[SocketAddr],
+    ) -> Option<Backend> {
         let key = request_selector_key(session, &self.inner.hash_on, self.inner.key.as_str());
         log::debug!("proxy lb key: {}", &key);
 
-        let mut backend = match &self.lb {
-            SelectionLB::RoundRobin(lb) => lb.upstreams.select(key.as_bytes(), 256),
-            SelectionLB::Random(lb) => lb.upstreams.select(key.as_bytes(), 256),
-            SelectionLB::Fnv(lb) => lb.upstreams.select(key.as_bytes(), 256),
-            SelectionLB::Ketama(lb) => lb.upstreams.select(key.as_bytes(), 256),
+        let mut backend = match self.select_from(key.as_bytes(), exclude) {
+            None if !exclude.is_empty() => self.select_from(key.as_bytes(), &[]),
+            backend => backend,
         };
 
         if let Some(backend) = backend.as_mut() {
@@ -121,6 +162,233 @@ impl ProxyUpstream {
         backend
     }
 
+    fn select_from(&self, key: &[u8], exclude: &[SocketAddr]) -> Option<Backend> {
+        if self.inner.slow_start.is_some() {
+            self.stats
+                .observe_backends(&self.lb.backends().get_backend());
+        }
+
+        // Hash selection keeps a key on its backend, warming backends get
+        // their full weight there
+        if matches!(self.lb, SelectionLB::RoundRobin(_) | SelectionLB::Random(_)) {
+            if let Some(backend) = self.select_warming(exclude) {
+                return Some(backend);
+            }
+        }
+
+        // Only the highest priority group with available backends is used
+        let priority = self
+            .has_priorities
+            .then(|| {
+                let backends = self.lb.backends();
+                self.ready_backends(backends, &backends.get_backend(), exclude)
+                    .first()
+                    .map(|backend| backend_priority(backend))
+            })
+            .flatten();
+
+        // Skip excluded backends and the ones marked unhealthy by the passive health check
+        let accept = |backend: &Backend, healthy: bool| {
+            healthy
+                && priority.is_none_or(|p| backend_priority(backend) == p)
+                && !exclude.contains(&backend.addr)
+                && self.passive_healthy(backend)
+        };
+
+        match &self.lb {
+            SelectionLB::RoundRobin(lb) => lb.upstreams.select_with(key, 256, accept),
+            SelectionLB::Random(lb) => lb.upstreams.select_with(key, 256, accept),
+            SelectionLB::Fnv(lb) => lb.upstreams.select_with(key, 256, accept),
+            SelectionLB::Ketama(lb) => lb.upstreams.select_with(key, 256, accept),
+            SelectionLB::LeastConn(lb) => self.select_least_conn(&lb.upstreams, exclude),
+            SelectionLB::Ewma(lb) => self.select_ewma(&lb.upstreams, exclude),
+        }
+    }
+
+    /// Selects the healthy backend with the fewest in-flight requests relative
+    /// to its weight, i.e. the lowest `(inflight + 1) / weight`.
+    fn select_least_conn(
+        &self,
+        lb: &LoadBalancer<RoundRobin>,
+        exclude: &[SocketAddr],
+    ) -> Option<Backend> {
+        let backends = lb.backends().get_backend();
+        let ready = self.ready_backends(lb.backends(), &backends, exclude);
+        if ready.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let stats = self.stats.backends.read().unwrap();
+        let load = |backend: &Backend| {
+            stats
+                .get(&backend.addr)
+                .map_or(0, |s| s.inflight.load(Ordering::Relaxed)) as f64
+                + 1.0
+        };
+        let weight = |backend: &Backend| {
+            backend.weight as f64 * self.warm_up_factor(stats.get(&backend.addr), now)
+        };
+
+        // Start from a rotating offset so ties do not always pick the same backend
+        let offset = self.cursor.fetch_add(1, Ordering::Relaxed) % ready.len();
+        ready
+            .iter()
+            .cycle()
+            .skip(offset)
+            .take(ready.len())
+            .min_by(|a, b| (load(a) * weight(b)).total_cmp(&(load(b) * weight(a))))
+            .map(|backend| (*backend).clone())
+    }
+
+    /// Selects a backend using peak-EWMA with power-of-two-choices: two random
+    /// healthy backends are compared and the one with the lowest
+    /// `ewma latency * (inflight + 1)` wins.
+    fn select_ewma(
+        &self,
+        lb: &LoadBalancer<RoundRobin>,
+        exclude: &[SocketAddr],
+    ) -> Option<Backend> {
+        let backends = lb.backends().get_backend();
+        let ready = self.ready_backends(lb.backends(), &backends, exclude);
+
+        let (a, b) = match ready.len() {
+            0 => return None,
+            1 => return Some(ready[0].clone()),
+            n => {
+                let mut rng = rand::thread_rng();
+                let a = rng.gen_range(0..n);
+                let b = (a + rng.gen_range(1..n)) % n;
+                (ready[a], ready[b])
+            }
+        };
+
+        let now = Instant::now();
+        let stats = self.stats.backends.read().unwrap();
+        let cost = |backend: &Backend| {
+            // Backends without samples have no cost, so they are tried first
+            stats.get(&backend.addr).map_or(0.0, |s| {
+                s.ewma.lock().unwrap().get(now) * (s.inflight.load(Ordering::Relaxed) + 1) as f64
+                    / self.warm_up_factor(Some(s), now)
+            })
+        };
+
+        if cost(b) < cost(a) {
+            Some(b.clone())
+        } else {
+            Some(a.clone())
+        }
+    }
+
+    /// Healthy backends with a non-zero weight, except the excluded ones.
+    ///
+    /// When the nodes are split into priority groups, only the backends of the
+    /// highest priority group with such backends are returned.
+    fn ready_backends<'a>(
+        &self,
+        lb: &Backends,
+        backends: &'a BTreeSet<Backend>,
+        exclude: &[SocketAddr],
+    ) -> Vec<&'a Backend> {
+        let ready: Vec<&Backend> = backends
+            .iter()
+            .filter(|backend| {
+                backend.weight > 0
+                    && !exclude.contains(&backend.addr)
+                    && lb.ready(backend)
+                    && self.passive_healthy(backend)
+            })
+            .collect();
+
+        if !self.has_priorities {
+            return ready;
+        }
+
+        let top = ready.iter().map(|backend| backend_priority(backend)).max();
+        ready
+            .into_iter()
+            .filter(|backend| Some(backend_priority(backend)) == top)
+            .collect()
+    }
+
+    /// Checks whether the passive health check allows traffic to the backend.
+    fn passive_healthy(&self, backend: &Backend) -> bool {
+        let Some(passive) = self.passive.as_ref() else {
+            return true;
+        };
+
+        self.stats.get(&backend.addr).is_none_or(|stats| {
+            stats.update_passive(|state| state.is_healthy(passive, Instant::now()))
+        })
+    }
+
+    /// Selects a ready backend at random in proportion to its weight scaled
+    /// by its warm-up, returns `None` when no ready backend is warming up.
+    fn select_warming(&self, exclude: &[SocketAddr]) -> Option<Backend> {
+        self.inner.slow_start?;
+
+        let backends = self.lb.backends();
+        let all = backends.get_backend();
+        let ready = self.ready_backends(backends, &all, exclude);
+
+        let now = Instant::now();
+        let stats = self.stats.backends.read().unwrap();
+        let factors: Vec<f64> = ready
+            .iter()
+            .map(|backend| self.warm_up_factor(stats.get(&backend.addr), now))
+            .collect();
+        if factors.iter().all(|factor| *factor >= 1.0) {
+            return None;
+        }
+
+        let weights: Vec<f64> = ready
+            .iter()
+            .zip(factors)
+            .map(|(backend, factor)| backend.weight as f64 * factor)
+            .collect();
+        let mut pick = rand::thread_rng().gen_range(0.0..weights.iter().sum::<f64>());
+        for (backend, weight) in ready.iter().zip(weights) {
+            if pick < weight {
+                return Some((*backend).clone());
+            }
+            pick -= weight;
+        }
+        ready.last().map(|backend| (*backend).clone())
+    }
+
+    /// Fraction of its weight a backend currently gets, below 1 during slow start.
+    fn warm_up_factor(&self, stats: Option<&Arc<BackendStats>>, now: Instant) -> f64 {
+        match (self.inner.slow_start, stats) {
+            (Some(slow_start), Some(stats)) => {
+                stats.warm_up_factor(Duration::from_secs(slow_start), now)
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Takes over the backend statistics of the upstream this one replaces, so
+    /// that only the nodes added by the update go through slow start.
+    pub fn inherit_stats(&self, previous: &ProxyUpstream) {
+        let backends = previous.stats.backends.read().unwrap().clone();
+        *self.stats.backends.write().unwrap() = backends;
+
+        let members = previous.stats.members.read().unwrap().clone();
+        *self.stats.members.write().unwrap() = members;
+    }
+
+    /// Marks a request as in flight to the backend until the guard is dropped.
+    pub fn acquire(&self, backend: &Backend) -> InflightGuard {
+        let stats = self.stats.get_or_create(backend);
+        stats.inflight.fetch_add(1, Ordering::Relaxed);
+        InflightGuard {
+            stats,
+            addr: backend.addr.clone(),
+            start: Instant::now(),
+            record_latency: self.inner.r#type == config::SelectionType::Ewma,
+            passive: self.passive.clone(),
+        }
+    }
+
     /// Rewrites the upstream host in the request header if needed.
     pub fn upstream_host_rewrite(&self, upstream_request: &mut RequestHeader) {
         if self.inner.pass_host == config::UpstreamPassHost::REWRITE {
@@ -146,6 +414,8 @@ impl ProxyUpstream {
             SelectionLB::Random(ref mut lb) => lb.service.take(),
             SelectionLB::Fnv(ref mut lb) => lb.service.take(),
             SelectionLB::Ketama(ref mut lb) => lb.service.take(),
+            SelectionLB::LeastConn(ref mut lb) => lb.service.take(),
+            SelectionLB::Ewma(ref mut lb) => lb.service.take(),
         }
     }
 
@@ -159,19 +429,58 @@ impl ProxyUpstream {
         self.inner.retry_timeout
     }
 
+    /// Checks whether `retries` and `retry_timeout` allow another attempt.
+    pub fn can_retry(&self, tries: usize, elapsed: Duration) -> bool {
+        let Some(retries) = self.get_retries() else {
+            return false;
+        };
+        if retries == 0 || tries >= retries {
+            return false;
+        }
+
+        self.get_retry_timeout()
+            .is_none_or(|timeout| elapsed.as_millis() <= (timeout * 1000) as _)
+    }
+
+    /// Checks whether a response status should be retried on another backend.
+    pub fn retry_on_status(&self, status: u16) -> bool {
+        self.inner
+            .retry_on
+            .as_ref()
+            .is_some_and(|retry_on| retry_on.http_statuses.contains(&status))
+    }
+
+    /// Checks whether upstream read and write timeouts should be retried.
+    pub fn retry_on_timeout(&self) -> bool {
+        self.inner
+            .retry_on
+            .as_ref()
+            .is_some_and(|retry_on| retry_on.timeout)
+    }
+
     /// Sets the timeout for an `HttpPeer`.
     fn set_timeout(&self, p: &mut HttpPeer) {
-        if let Some(config::Timeout {
-            connect,
-            read,
-            send,
-        }) = self.inner.timeout
-        {
-            p.options.connection_timeout = Some(Duration::from_secs(connect));
-            p.options.read_timeout = Some(Duration::from_secs(read));
-            p.options.write_timeout = Some(Duration::from_secs(send));
+        if let Some(timeout) = self.inner.timeout.as_ref() {
+            apply_timeout(timeout, p);
+        }
+
+        if let Some(pool) = self.inner.keepalive_pool.as_ref() {
+            p.options.idle_timeout = Some(Duration::from_secs(pool.idle_timeout));
         }
     }
+
+    /// Tracks a request on an upstream connection to apply the keepalive pool
+    /// limits, returns `None` without a keepalive pool configuration.
+    pub fn track_connection(&self, fd: RawFd, reused: bool) -> Option<ConnectionGuard> {
+        let pool = self.inner.keepalive_pool.as_ref()?;
+        let close = self.connections.acquire(pool, fd, reused, Instant::now());
+
+        Some(ConnectionGuard {
+            tracker: self.connections.clone(),
+            fd,
+            close,
+        })
+    }
 }
 
 impl Drop for ProxyUpstream {
@@ -194,28 +503,431 @@ impl Drop for ProxyUpstream {
     }
 }
 
+/// Minimum fraction of its weight a backend in slow start gets.
+const MIN_WARM_UP_FACTOR: f64 = 0.1;
+
+/// Runtime statistics of the backends of an upstream, keyed by address.
+#[derive(Default)]
+struct UpstreamStats {
+    backends: RwLock<HashMap<SocketAddr, Arc<BackendStats>>>,
+    /// The last observed backend set, used to detect added backends
+    members: RwLock<Option<Arc<BTreeSet<Backend>>>>,
+}
+
+impl UpstreamStats {
+    fn get(&self, addr: &SocketAddr) -> Option<Arc<BackendStats>> {
+        self.backends.read().unwrap().get(addr).cloned()
+    }
+
+    /// Gets the statistics of a backend, creating them on first use.
+    fn get_or_create(&self, backend: &Backend) -> Arc<BackendStats> {
+        if let Some(stats) = self.get(&backend.addr) {
+            return stats;
+        }
+
+        self.backends
+            .write()
+            .unwrap()
+            .entry(backend.addr.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Starts the slow start of the backends added since the last observed
+    /// backend set, the initial set is taken as is.
+    fn observe_backends(&self, backends: &Arc<BTreeSet<Backend>>) {
+        let is_known = |members: &Option<Arc<BTreeSet<Backend>>>| {
+            members
+                .as_ref()
+                .is_some_and(|members| Arc::ptr_eq(members, backends))
+        };
+
+        // Discovery may not have run yet
+        if backends.is_empty() || is_known(&self.members.read().unwrap()) {
+            return;
+        }
+
+        let mut members = self.members.write().unwrap();
+        if is_known(&members) {
+            return;
+        }
+
+        if let Some(previous) = members.as_ref() {
+            let known: HashSet<&SocketAddr> = previous.iter().map(|b| &b.addr).collect();
+            for backend in backends.iter().filter(|b| !known.contains(&b.addr)) {
+                log::info!("Backend {} added, starting slow start", backend.addr);
+                self.get_or_create(backend).start_warm_up();
+            }
+        }
+        *members = Some(backends.clone());
+    }
+}
+
+/// Runtime statistics of a single backend.
+#[derive(Default)]
+struct BackendStats {
+    /// Number of requests currently proxied to the backend
+    inflight: AtomicUsize,
+    /// Response latency moving average, only updated for `ewma` upstreams
+    ewma: Mutex<Ewma>,
+    /// Passive health check state
+    passive: Mutex<PassiveState>,
+    /// Consecutive failed active checks
+    active_failures: AtomicUsize,
+    /// Set while the backend is in slow start
+    warm_since: Mutex<Option<Instant>>,
+}
+
+impl BackendStats {
+    /// Runs `f` on the passive health check state, starting the slow start of
+    /// the backend if it recovers.
+    fn update_passive<T>(&self, f: impl FnOnce(&mut PassiveState) -> T) -> T {
+        let mut passive = self.passive.lock().unwrap();
+        let was_unhealthy = passive.unhealthy_since.is_some();
+        let result = f(&mut passive);
+        if was_unhealthy && passive.unhealthy_since.is_none() {
+            self.start_warm_up();
+        }
+        result
+    }
+
+    fn start_warm_up(&self) {
+        *self.warm_since.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn warm_up_factor(&self, slow_start: Duration, now: Instant) -> f64 {
+        let mut warm_since = self.warm_since.lock().unwrap();
+        let factor = warm_since.map_or(1.0, |since| warm_up_factor(since, slow_start, now));
+        if factor >= 1.0 {
+            *warm_since = None;
+        }
+        factor
+    }
+}
+
+/// Ramps linearly from `MIN_WARM_UP_FACTOR` to 1 over the slow start window.
+fn warm_up_factor(since: Instant, slow_start: Duration, now: Instant) -> f64 {
+    let elapsed = now.saturating_duration_since(since);
+    if elapsed >= slow_start {
+        return 1.0;
+    }
+
+    (elapsed.as_secs_f64() / slow_start.as_secs_f64()).max(MIN_WARM_UP_FACTOR)
+}
+
+/// An event observed on a proxied request, reported to the passive health check.
+pub enum PassiveEvent {
+    /// The upstream responded with a status code
+    Status(u16),
+    /// The connection to the upstream failed
+    TcpFailure,
+    /// Connecting, reading from or writing to the upstream timed out
+    Timeout,
+}
+
+/// Passive health check counters of a backend.
+#[derive(Default)]
+struct PassiveState {
+    successes: u32,
+    http_failures: u32,
+    tcp_failures: u32,
+    timeouts: u32,
+    /// Consecutive successful active checks while unhealthy
+    active_successes: usize,
+    /// Set while the backend is considered unhealthy
+    unhealthy_since: Option<Instant>,
+}
+
+impl PassiveState {
+    /// Checks whether the backend is healthy, recovering it once the recovery
+    /// timeout has elapsed.
+    fn is_healthy(&mut self, check: &config::PassiveCheck, now: Instant) -> bool {
+        match self.unhealthy_since {
+            Some(since)
+                if now.saturating_duration_since(since)
+                    < Duration::from_secs(check.recovery_timeout) =>
+            {
+                false
+            }
+            Some(_) => {
+                self.recover();
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Records an event, returns true if the backend just became unhealthy.
+    fn report(&mut self, check: &config::PassiveCheck, event: PassiveEvent, now: Instant) -> bool {
+        let (count, threshold) = match event {
+            PassiveEvent::Status(status) => {
+                let status = status as u32;
+                if check.unhealthy.http_statuses.contains(&status) {
+                    self.http_failures += 1;
+                    (self.http_failures, check.unhealthy.http_failures)
+                } else {
+                    if check.healthy.http_statuses.contains(&status) {
+                        self.report_success(check);
+                    }
+                    return false;
+                }
+            }
+            PassiveEvent::TcpFailure => {
+                self.tcp_failures += 1;
+                (self.tcp_failures, check.unhealthy.tcp_failures)
+            }
+            PassiveEvent::Timeout => {
+                self.timeouts += 1;
+                (self.timeouts, check.unhealthy.timeouts)
+            }
+        };
+
+        self.successes = 0;
+        if self.unhealthy_since.is_none() && threshold > 0 && count >= threshold {
+            self.unhealthy_since = Some(now);
+            return true;
+        }
+        false
+    }
+
+    fn report_success(&mut self, check: &config::PassiveCheck) {
+        self.successes += 1;
+        self.http_failures = 0;
+        self.tcp_failures = 0;
+        self.timeouts = 0;
+
+        if self.unhealthy_since.is_some() && self.successes >= check.healthy.successes {
+            self.recover();
+        }
+    }
+
+    /// Records a successful active check, the backend recovers once the active
+    /// healthy threshold is reached.
+    fn report_active_success(&mut self, threshold: usize) {
+        if self.unhealthy_since.is_none() {
+            return;
+        }
+
+        self.active_successes += 1;
+        if self.active_successes >= threshold {
+            self.recover();
+        }
+    }
+
+    fn recover(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Peak exponentially weighted moving average of the response latency.
+#[derive(Default)]
+struct Ewma {
+    /// Latency in milliseconds
+    value: f64,
+    updated: Option<Instant>,
+}
+
+impl Ewma {
+    /// Time after which a sample has decayed to `1/e` of its weight.
+    const DECAY_TIME: Duration = Duration::from_secs(10);
+
+    /// Returns the average decayed to `now`, so idle backends are retried.
+    fn get(&self, now: Instant) -> f64 {
+        self.updated.map_or(self.value, |updated| {
+            self.value * Self::weight(updated, now)
+        })
+    }
+
+    /// Records a latency sample, peaks are taken immediately.
+    fn observe(&mut self, latency: Duration, now: Instant) {
+        let latency = latency.as_secs_f64() * 1000.0;
+
+        self.value = if latency > self.value {
+            latency
+        } else {
+            let weight = self
+                .updated
+                .map_or(0.0, |updated| Self::weight(updated, now));
+            self.value * weight + latency * (1.0 - weight)
+        };
+        self.updated = Some(now);
+    }
+
+    fn weight(updated: Instant, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(updated).as_secs_f64();
+        (-elapsed / Self::DECAY_TIME.as_secs_f64()).exp()
+    }
+}
+
+/// Tracks the connections to an upstream to apply its keepalive pool limits,
+/// as pingora only bounds the size of its global connection pool.
+///
+/// Pingora does not report the pooled connections it closes, e.g. when the
+/// upstream closes them first, so these are still counted as idle until the
+/// idle timeout. Meanwhile requests may close their connection while the
+/// pool is not actually full, which only costs reuse, not correctness.
+#[derive(Default)]
+struct ConnectionTracker {
+    connections: Mutex<HashMap<RawFd, ConnectionState>>,
+}
+
+struct ConnectionState {
+    requests: usize,
+    busy: bool,
+    last_used: Instant,
+}
+
+impl ConnectionTracker {
+    /// Records a request on a connection, returns true if the connection must
+    /// be closed after the request.
+    fn acquire(&self, pool: &config::KeepalivePool, fd: RawFd, reused: bool, now: Instant) -> bool {
+        let mut connections = self.connections.lock().unwrap();
+
+        // Forget the connections the pool has closed by now
+        let idle_timeout = Duration::from_secs(pool.idle_timeout);
+        connections.retain(|_, conn| {
+            conn.busy || now.saturating_duration_since(conn.last_used) < idle_timeout
+        });
+
+        let idle = connections
+            .iter()
+            .filter(|(other, conn)| **other != fd && !conn.busy)
+            .count();
+
+        let conn = connections.entry(fd).or_insert(ConnectionState {
+            requests: 0,
+            busy: false,
+            last_used: now,
+        });
+        // The descriptor may belong to a closed connection
+        if !reused {
+            conn.requests = 0;
+        }
+        conn.requests += 1;
+        conn.busy = true;
+        conn.last_used = now;
+
+        let close = conn.requests >= pool.requests || idle >= pool.size;
+        if close {
+            connections.remove(&fd);
+        }
+        close
+    }
+
+    fn release(&self, fd: RawFd, now: Instant) {
+        if let Some(conn) = self.connections.lock().unwrap().get_mut(&fd) {
+            conn.busy = false;
+            conn.last_used = now;
+        }
+    }
+}
+
+/// A request on an upstream connection, released when dropped.
+pub struct ConnectionGuard {
+    tracker: Arc<ConnectionTracker>,
+    fd: RawFd,
+    close: bool,
+}
+
+impl ConnectionGuard {
+    /// Whether the connection must be closed after the request.
+    pub fn close(&self) -> bool {
+        self.close
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if !self.close {
+            self.tracker.release(self.fd, Instant::now());
+        }
+    }
+}
+
+/// An in-flight request to a backend, released when dropped.
+pub struct InflightGuard {
+    stats: Arc<BackendStats>,
+    addr: SocketAddr,
+    start: Instant,
+    record_latency: bool,
+    passive: Option<Arc<config::PassiveCheck>>,
+}
+
+impl InflightGuard {
+    /// Reports an event of the request to the passive health check, if enabled.
+    pub fn report_passive(&self, event: PassiveEvent) {
+        let Some(passive) = self.passive.as_ref() else {
+            return;
+        };
+
+        let unhealthy = self
+            .stats
+            .update_passive(|state| state.report(passive, event, Instant::now()));
+        if unhealthy {
+            log::warn!(
+                "Backend {} marked unhealthy by passive health check",
+                self.addr
+            );
+        }
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.stats.inflight.fetch_sub(1, Ordering::Relaxed);
+
+        if self.record_latency {
+            let now = Instant::now();
+            self.stats
+                .ewma
+                .lock()
+                .unwrap()
+                .observe(now.saturating_duration_since(self.start), now);
+        }
+    }
+}
+
 enum SelectionLB {
     RoundRobin(LB<RoundRobin>),
     Random(LB<Random>),
     Fnv(LB<FVNHash>),
     Ketama(LB<KetamaHashing>),
+    // For the following types backends are only discovered and health checked
+    // by the load balancer, the selection itself is based on `BackendStats`
+    LeastConn(LB<RoundRobin>),
+    Ewma(LB<RoundRobin>),
 }
 
-impl TryFrom<config::Upstream> for SelectionLB {
-    type Error = Box<Error>;
-
-    fn try_from(value: config::Upstream) -> Result<Self> {
+impl SelectionLB {
+    fn new(value: config::Upstream, stats: &Arc<UpstreamStats>) -> Result<Self> {
         match value.r#type {
-            config::SelectionType::RoundRobin => {
-                Ok(SelectionLB::RoundRobin(LB::<RoundRobin>::try_from(value)?))
-            }
+            config::SelectionType::RoundRobin => Ok(SelectionLB::RoundRobin(
+                LB::<RoundRobin>::new(value, stats)?,
+            )),
             config::SelectionType::Random => {
-                Ok(SelectionLB::Random(LB::<Random>::try_from(value)?))
+                Ok(SelectionLB::Random(LB::<Random>::new(value, stats)?))
             }
-            config::SelectionType::Fnv => Ok(SelectionLB::Fnv(LB::<FVNHash>::try_from(value)?)),
+            config::SelectionType::Fnv => Ok(SelectionLB::Fnv(LB::<FVNHash>::new(value, stats)?)),
             config::SelectionType::Ketama => {
-                Ok(SelectionLB::Ketama(LB::<KetamaHashing>::try_from(value)?))
+                Ok(SelectionLB::Ketama(LB::<KetamaHashing>::new(value, stats)?))
+            }
+            config::SelectionType::LeastConn => {
+                Ok(SelectionLB::LeastConn(LB::<RoundRobin>::new(value, stats)?))
             }
+            config::SelectionType::Ewma => {
+                Ok(SelectionLB::Ewma(LB::<RoundRobin>::new(value, stats)?))
+            }
+        }
+    }
+
+    fn backends(&self) -> &Backends {
+        match self {
+            SelectionLB::RoundRobin(lb) => lb.upstreams.backends(),
+            SelectionLB::Random(lb) => lb.upstreams.backends(),
+            SelectionLB::Fnv(lb) => lb.upstreams.backends(),
+            SelectionLB::Ketama(lb) => lb.upstreams.backends(),
+            SelectionLB::LeastConn(lb) => lb.upstreams.backends(),
+            SelectionLB::Ewma(lb) => lb.upstreams.backends(),
         }
     }
 }
@@ -225,24 +937,26 @@ struct LB<BS: BackendSelection> {
     service: Option<Box<dyn Service + 'static>>,
 }
 
-impl<BS> TryFrom<config::Upstream> for LB<BS>
+impl<BS> LB<BS>
 where
     BS: BackendSelection + Send + Sync + 'static,
     BS::Iter: BackendIter,
 {
-    type Error = Box<Error>;
-
-    fn try_from(upstream: config::Upstream) -> Result<Self> {
+    fn new(upstream: config::Upstream, stats: &Arc<UpstreamStats>) -> Result<Self> {
         let discovery: HybridDiscovery = upstream.clone().try_into()?;
+        let update_frequency = discovery.update_frequency();
         let mut upstreams = LoadBalancer::<BS>::from_backends(Backends::new(Box::new(discovery)));
+        upstreams.update_frequency = update_frequency;
 
-        if let Some(check) = upstream.checks {
-            let health_check: Box<(dyn HealthCheckTrait + Send + Sync + 'static)> =
-                check.clone().into();
-            upstreams.set_health_check(health_check);
+        if let Some(active) = upstream.checks.and_then(|checks| checks.active) {
+            let health_check: Box<dyn HealthCheckTrait + Send + Sync + 'static> =
+                active.clone().into();
+            upstreams.set_health_check(Box::new(ActiveCheckRecovery {
+                check: health_check,
+                stats: stats.clone(),
+            }));
 
-            let health_check_frequency = check
-                .active
+            let health_check_frequency = active
                 .healthy
                 .map(|healthy| Duration::from_secs(healthy.interval as _))
                 .unwrap_or(Duration::from_secs(1));
@@ -262,9 +976,47 @@ where
     }
 }
 
-impl From<config::HealthCheck> for Box<(dyn HealthCheckTrait + Send + Sync + 'static)> {
-    fn from(value: config::HealthCheck) -> Self {
-        match value.active.r#type {
+/// Active health check that also recovers backends marked unhealthy by the
+/// passive health check.
+struct ActiveCheckRecovery {
+    check: Box<dyn HealthCheckTrait + Send + Sync>,
+    stats: Arc<UpstreamStats>,
+}
+
+#[async_trait]
+impl HealthCheckTrait for ActiveCheckRecovery {
+    async fn check(&self, target: &Backend) -> Result<()> {
+        let result = self.check.check(target).await;
+
+        let stats = self.stats.get_or_create(target);
+        match result {
+            Ok(_) => {
+                // The backend was marked unhealthy by the active check and recovers
+                if stats.active_failures.swap(0, Ordering::Relaxed) >= self.health_threshold(false)
+                {
+                    stats.start_warm_up();
+                }
+                stats.update_passive(|state| {
+                    state.report_active_success(self.health_threshold(true))
+                });
+            }
+            Err(_) => {
+                stats.active_failures.fetch_add(1, Ordering::Relaxed);
+                stats.passive.lock().unwrap().active_successes = 0;
+            }
+        }
+
+        result
+    }
+
+    fn health_threshold(&self, success: bool) -> usize {
+        self.check.health_threshold(success)
+    }
+}
+
+impl From<config::ActiveCheck> for Box<dyn HealthCheckTrait + Send + Sync + 'static> {
+    fn from(value: config::ActiveCheck) -> Self {
+        match value.r#type {
             config::ActiveCheckType::TCP => {
                 let health_check: Box<TcpHealthCheck> = value.into();
                 health_check
@@ -277,17 +1029,17 @@ impl From<config::HealthCheck> for Box<(dyn HealthCheckTrait + Send + Sync + 'st
     }
 }
 
-impl From<config::HealthCheck> for Box<TcpHealthCheck> {
-    fn from(value: config::HealthCheck) -> Self {
+impl From<config::ActiveCheck> for Box<TcpHealthCheck> {
+    fn from(value: config::ActiveCheck) -> Self {
         let mut health_check = TcpHealthCheck::new();
         health_check.peer_template.options.total_connection_timeout =
-            Some(Duration::from_secs(value.active.timeout as _));
+            Some(Duration::from_secs(value.timeout as _));
 
-        if let Some(healthy) = value.active.healthy {
+        if let Some(healthy) = value.healthy {
             health_check.consecutive_success = healthy.successes as _;
         }
 
-        if let Some(unhealthy) = value.active.unhealthy {
+        if let Some(unhealthy) = value.unhealthy {
             health_check.consecutive_failure = unhealthy.tcp_failures as _;
         }
 
@@ -295,34 +1047,31 @@ impl From<config::HealthCheck> for Box<TcpHealthCheck> {
     }
 }
 
-impl From<config::HealthCheck> for Box<HttpHealthCheck> {
-    fn from(value: config::HealthCheck) -> Self {
-        let host = value.active.host.unwrap_or_default();
-        let tls = value.active.r#type == config::ActiveCheckType::HTTPS;
+impl From<config::ActiveCheck> for Box<HttpHealthCheck> {
+    fn from(value: config::ActiveCheck) -> Self {
+        let host = value.host.unwrap_or_default();
+        let tls = value.r#type == config::ActiveCheckType::HTTPS;
         let mut health_check = HttpHealthCheck::new(host.as_str(), tls);
 
         // Set total connection timeout if provided
         health_check.peer_template.options.total_connection_timeout =
-            Some(Duration::from_secs(value.active.timeout as _));
+            Some(Duration::from_secs(value.timeout as _));
 
         // Set certificate verification if TLS is enabled
-        health_check.peer_template.options.verify_cert = value.active.https_verify_certificate;
+        health_check.peer_template.options.verify_cert = value.https_verify_certificate;
 
         // Build URI for HTTP health check path, log failure if any
-        if let Ok(uri) = Uri::builder()
-            .path_and_query(&value.active.http_path)
-            .build()
-        {
+        if let Ok(uri) = Uri::builder().path_and_query(&value.http_path).build() {
             health_check.req.set_uri(uri);
         } else {
             log::warn!(
                 "Invalid URI path provided for health check: {}",
-                value.active.http_path
+                value.http_path
             );
         }
 
         // Insert headers, ensure they are properly formatted
-        for header in value.active.req_headers.iter() {
+        for header in value.req_headers.iter() {
             let mut parts = header.splitn(2, ":");
             if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
                 let key = key.trim().to_string();
@@ -332,12 +1081,12 @@ impl From<config::HealthCheck> for Box<HttpHealthCheck> {
         }
 
         // Handle port override
-        if let Some(port) = value.active.port {
-            health_check.port_override = Some(port as _);
+        if let Some(port) = value.port {
+            health_check.port_override = Some(port);
         }
 
         // Set the success conditions
-        if let Some(healthy) = value.active.healthy {
+        if let Some(healthy) = value.healthy {
             health_check.consecutive_success = healthy.successes as _;
 
             // Validator for HTTP status codes
@@ -354,7 +1103,7 @@ impl From<config::HealthCheck> for Box<HttpHealthCheck> {
         }
 
         // Set the failure conditions
-        if let Some(unhealthy) = value.active.unhealthy {
+        if let Some(unhealthy) = value.unhealthy {
             health_check.consecutive_failure = unhealthy.http_failures as _;
         }
 
@@ -404,3 +1153,306 @@ pub fn upstream_fetch(id: &str) -> Option<Arc<ProxyUpstream>> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::plugin::test_session;
+
+    fn upstream(cfg: &str) -> ProxyUpstream {
+        ProxyUpstream::try_from(serde_yaml::from_str::<config::Upstream>(cfg).unwrap()).unwrap()
+    }
+
+    /// Loads the static nodes of an upstream into its load balancer.
+    async fn update(upstream: &ProxyUpstream) {
+        match &upstream.lb {
+            SelectionLB::RoundRobin(lb) => lb.upstreams.update().await.unwrap(),
+            SelectionLB::Random(lb) => lb.upstreams.update().await.unwrap(),
+            SelectionLB::Fnv(lb) => lb.upstreams.update().await.unwrap(),
+            SelectionLB::Ketama(lb) => lb.upstreams.update().await.unwrap(),
+            SelectionLB::LeastConn(lb) => lb.upstreams.update().await.unwrap(),
+            SelectionLB::Ewma(lb) => lb.upstreams.update().await.unwrap(),
+        }
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        SocketAddr::Inet(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_can_retry() {
+        let upstream = self::upstream(
+            "{retries: 2, retry_timeout: 1, nodes: [{host: 127.0.0.1, port: 1980, weight: 1}]}",
+        );
+        assert!(upstream.can_retry(0, Duration::ZERO));
+        assert!(upstream.can_retry(1, Duration::from_millis(1000)));
+        // Out of retries or past the retry timeout
+        assert!(!upstream.can_retry(2, Duration::ZERO));
+        assert!(!upstream.can_retry(0, Duration::from_millis(1001)));
+
+        let upstream =
+            self::upstream("{retries: 0, nodes: [{host: 127.0.0.1, port: 1980, weight: 1}]}");
+        assert!(!upstream.can_retry(0, Duration::ZERO));
+
+        let upstream = self::upstream("{nodes: [{host: 127.0.0.1, port: 1980, weight: 1}]}");
+        assert!(!upstream.can_retry(0, Duration::ZERO));
+    }
+
+    #[test]
+    fn test_retry_on() {
+        let upstream = self::upstream(
+            "{retry_on: {http_statuses: [502, 503], timeout: true}, nodes: [{host: 127.0.0.1, port: 1980, weight: 1}]}",
+        );
+        assert!(upstream.retry_on_status(502));
+        assert!(upstream.retry_on_status(503));
+        assert!(!upstream.retry_on_status(500));
+        assert!(upstream.retry_on_timeout());
+
+        let upstream = self::upstream(
+            "{retry_on: {http_statuses: [502]}, nodes: [{host: 127.0.0.1, port: 1980, weight: 1}]}",
+        );
+        assert!(!upstream.retry_on_timeout());
+
+        let upstream = self::upstream("{nodes: [{host: 127.0.0.1, port: 1980, weight: 1}]}");
+        assert!(!upstream.retry_on_status(502));
+        assert!(!upstream.retry_on_timeout());
+    }
+
+    #[tokio::test]
+    async fn test_select_backend_excludes_tried() {
+        let upstream = self::upstream(
+            "{nodes: [{host: 127.0.0.1, port: 1980, weight: 1}, {host: 127.0.0.1, port: 1981, weight: 1}]}",
+        );
+        update(&upstream).await;
+        let (mut session, _client) = test_session("GET / HTTP/1.1\r\n\r\n").await;
+
+        let tried = [addr("127.0.0.1:1980")];
+        for _ in 0..4 {
+            let backend = upstream.select_backend(&mut session, &tried).unwrap();
+            assert_eq!(backend.addr, addr("127.0.0.1:1981"));
+        }
+
+        // Tried backends are selected again when no other one is left
+        let tried = [addr("127.0.0.1:1980"), addr("127.0.0.1:1981")];
+        assert!(upstream.select_backend(&mut session, &tried).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_select_warming() {
+        let (mut session, _client) = test_session("GET / HTTP/1.1\r\n\r\n").await;
+
+        for (r#type, ramps) in [("roundrobin", true), ("random", true), ("fnv", false)] {
+            let upstream = self::upstream(&format!(
+                "{{type: {}, slow_start: 60, nodes: [{{host: 127.0.0.1, port: 1980, weight: 1}}, {{host: 127.0.0.1, port: 1981, weight: 1}}]}}",
+                r#type
+            ));
+            update(&upstream).await;
+
+            // Warm up the backend the request key is hashed to
+            let warming = upstream.select_backend(&mut session, &[]).unwrap();
+            upstream.stats.get_or_create(&warming).start_warm_up();
+
+            let picked = (0..1000)
+                .filter(|_| {
+                    upstream.select_backend(&mut session, &[]).unwrap().addr == warming.addr
+                })
+                .count();
+            if ramps {
+                // 0.1 / 1.1 of the requests
+                assert!((30..200).contains(&picked), "{}: {}", r#type, picked);
+            } else {
+                assert_eq!(picked, 1000, "{}", r#type);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select_least_conn() {
+        let upstream = self::upstream(
+            r#"
+type: least_conn
+checks:
+  passive:
+    unhealthy:
+      tcp_failures: 1
+nodes:
+  - {host: 127.0.0.1, port: 1980, weight: 2}
+  - {host: 127.0.0.1, port: 1981, weight: 1}
+  - {host: 127.0.0.1, port: 1982, weight: 1}
+"#,
+        );
+        update(&upstream).await;
+        let (mut session, _client) = test_session("GET / HTTP/1.1\r\n\r\n").await;
+        let mut select =
+            |exclude: &[SocketAddr]| upstream.select_backend(&mut session, exclude).unwrap();
+        let (a, b, c) = (
+            addr("127.0.0.1:1980"),
+            addr("127.0.0.1:1981"),
+            addr("127.0.0.1:1982"),
+        );
+
+        let first = select(&[]);
+        assert_eq!(first.addr, a);
+        let _a1 = upstream.acquire(&first);
+        let _a2 = upstream.acquire(&first);
+        let _b1 = upstream.acquire(&select(&[a.clone(), c.clone()]));
+        let _c1 = upstream.acquire(&select(&[a.clone(), b.clone()]));
+        // (2 + 1) / 2 < (1 + 1) / 1: the heavier backend takes more requests
+        assert_eq!(select(&[]).addr, a);
+
+        // Ties between equally loaded backends rotate
+        let picked: HashSet<SocketAddr> = (0..3)
+            .map(|_| select(std::slice::from_ref(&a)).addr)
+            .collect();
+        assert_eq!(picked, HashSet::from([b.clone(), c.clone()]));
+
+        // Unhealthy backends are skipped
+        let c2 = upstream.acquire(&select(&[a.clone(), b.clone()]));
+        c2.report_passive(PassiveEvent::TcpFailure);
+        drop(c2);
+        for _ in 0..3 {
+            assert_eq!(select(std::slice::from_ref(&a)).addr, b);
+        }
+    }
+
+    #[test]
+    fn test_ewma_peak_and_decay() {
+        let start = Instant::now();
+        let mut ewma = Ewma::default();
+
+        ewma.observe(Duration::from_millis(100), start);
+        assert_eq!(ewma.get(start), 100.0);
+
+        // Peaks are taken immediately
+        ewma.observe(Duration::from_millis(500), start);
+        assert_eq!(ewma.get(start), 500.0);
+
+        // Lower samples are averaged in
+        let later = start + Duration::from_secs(1);
+        ewma.observe(Duration::from_millis(100), later);
+        let value = ewma.get(later);
+        assert!(value > 100.0 && value < 500.0, "value: {}", value);
+
+        // Idle backends decay towards zero
+        assert!(ewma.get(later + Duration::from_secs(120)) < 1.0);
+    }
+
+    #[test]
+    fn test_warm_up_factor() {
+        let start = Instant::now();
+        let slow_start = Duration::from_secs(60);
+
+        assert_eq!(warm_up_factor(start, slow_start, start), MIN_WARM_UP_FACTOR);
+        assert_eq!(
+            warm_up_factor(start, slow_start, start + Duration::from_secs(30)),
+            0.5
+        );
+        assert_eq!(
+            warm_up_factor(start, slow_start, start + Duration::from_secs(60)),
+            1.0
+        );
+    }
+
+    #[test]
+    fn test_connection_tracker() {
+        let pool: config::KeepalivePool =
+            serde_yaml::from_str("{size: 1, idle_timeout: 10, requests: 3}").unwrap();
+        let tracker = ConnectionTracker::default();
+        let start = Instant::now();
+
+        // Closed after the maximum number of requests
+        assert!(!tracker.acquire(&pool, 1, false, start));
+        tracker.release(1, start);
+        assert!(!tracker.acquire(&pool, 1, true, start));
+        tracker.release(1, start);
+        assert!(tracker.acquire(&pool, 1, true, start));
+
+        // Closed when the pool already holds enough idle connections
+        assert!(!tracker.acquire(&pool, 2, false, start));
+        tracker.release(2, start);
+        assert!(tracker.acquire(&pool, 3, false, start));
+
+        // Idle connections expire after the idle timeout
+        let later = start + Duration::from_secs(10);
+        assert!(!tracker.acquire(&pool, 4, false, later));
+    }
+
+    #[test]
+    fn test_connection_tracker_stale() {
+        let pool: config::KeepalivePool =
+            serde_yaml::from_str("{size: 1, idle_timeout: 10, requests: 100}").unwrap();
+        let tracker = ConnectionTracker::default();
+        let start = Instant::now();
+
+        // Connection 1 goes idle, then is closed by the upstream unnoticed
+        assert!(!tracker.acquire(&pool, 1, false, start));
+        tracker.release(1, start);
+
+        // The stale entry still fills the pool until the idle timeout
+        assert!(tracker.acquire(&pool, 2, false, start));
+        let later = start + Duration::from_secs(5);
+        assert!(tracker.acquire(&pool, 3, false, later));
+        let expired = start + Duration::from_secs(10);
+        assert!(!tracker.acquire(&pool, 3, false, expired));
+
+        // A new connection reusing the descriptor starts counting from scratch
+        tracker.release(3, expired);
+        for _ in 0..3 {
+            assert!(!tracker.acquire(&pool, 3, true, expired));
+            tracker.release(3, expired);
+        }
+        assert!(!tracker.acquire(&pool, 3, false, expired));
+        assert_eq!(tracker.connections.lock().unwrap()[&3].requests, 1);
+    }
+
+    #[test]
+    fn test_passive_state() {
+        let check: config::PassiveCheck = serde_yaml::from_str(
+            r#"
+            recovery_timeout: 10
+            healthy:
+              successes: 2
+            unhealthy:
+              http_failures: 2
+              tcp_failures: 0
+            "#,
+        )
+        .unwrap();
+        let start = Instant::now();
+        let mut state = PassiveState::default();
+
+        // Successes reset the failure counter
+        assert!(!state.report(&check, PassiveEvent::Status(500), start));
+        assert!(!state.report(&check, PassiveEvent::Status(200), start));
+        assert!(!state.report(&check, PassiveEvent::Status(503), start));
+        assert!(state.is_healthy(&check, start));
+
+        // A zero threshold disables the counter
+        for _ in 0..5 {
+            assert!(!state.report(&check, PassiveEvent::TcpFailure, start));
+        }
+
+        assert!(state.report(&check, PassiveEvent::Status(500), start));
+        assert!(!state.is_healthy(&check, start));
+
+        // Recovered by successful responses
+        state.report(&check, PassiveEvent::Status(200), start);
+        state.report(&check, PassiveEvent::Status(200), start);
+        assert!(state.is_healthy(&check, start));
+
+        // Recovered by active checks
+        state.report(&check, PassiveEvent::Status(500), start);
+        state.report(&check, PassiveEvent::Status(500), start);
+        assert!(!state.is_healthy(&check, start));
+        state.report_active_success(2);
+        assert!(!state.is_healthy(&check, start));
+        state.report_active_success(2);
+        assert!(state.is_healthy(&check, start));
+
+        // Recovered after the recovery timeout
+        state.report(&check, PassiveEvent::Status(500), start);
+        state.report(&check, PassiveEvent::Status(500), start);
+        assert!(!state.is_healthy(&check, start + Duration::from_secs(5)));
+        assert!(state.is_healthy(&check, start + Duration::from_secs(10)));
+    }
+}
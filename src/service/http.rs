@@ -1,14 +1,17 @@
-use std::time::Duration;
+use std::{os::unix::io::RawFd, time::Duration};
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use http::StatusCode;
+use http::{header, Method, StatusCode};
 use pingora::modules::http::{
     HttpModules,
     {compression::ResponseCompressionBuilder, grpc_web::GrpcWeb},
 };
-use pingora_core::upstreams::peer::HttpPeer;
-use pingora_error::{Error, Result};
+use pingora_core::{
+    protocols::{Digest, ALPN},
+    upstreams::peer::HttpPeer,
+};
+use pingora_error::{Error, ErrorSource, ErrorType, Result};
 use pingora_http::{RequestHeader, ResponseHeader};
 use pingora_proxy::{ProxyHttp, Session};
 
@@ -16,6 +19,7 @@ use crate::proxy::{
     global_rule::global_plugin_fetch,
     plugin::{build_plugin_executor, ProxyPlugin},
     route::global_match_fetch,
+    upstream::PassiveEvent,
     ProxyContext,
 };
 
@@ -40,7 +44,12 @@ impl ProxyHttp for HttpService {
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
-        let peer = ctx.route.as_ref().unwrap().select_http_peer(session);
+        // Release the backend of a previous attempt before selecting a new one
+        ctx.inflight = None;
+        ctx.connection = None;
+
+        let route = ctx.route.clone().unwrap();
+        let peer = route.select_http_peer(session, ctx);
         if let Ok(ref peer) = peer {
             ctx.vars
                 .insert("upstream".to_string(), peer._address.to_string());
@@ -117,6 +126,53 @@ impl ProxyHttp for HttpService {
         if let Some(upstream) = ctx.route.as_ref().and_then(|r| r.resolve_upstream()) {
             upstream.upstream_host_rewrite(upstream_request);
         }
+
+        // close the connection once the keepalive pool limits are reached
+        if ctx.connection.as_ref().is_some_and(|conn| conn.close()) {
+            upstream_request.insert_header(header::CONNECTION, "close")?;
+        }
+        Ok(())
+    }
+
+    async fn request_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        // execute global rule plugins
+        global_plugin_fetch()
+            .request_body_filter(session, body, end_of_stream, ctx)
+            .await?;
+
+        // execute plugins
+        ctx.plugin
+            .clone()
+            .request_body_filter(session, body, end_of_stream, ctx)
+            .await
+    }
+
+    /// This filter is called when the connection to the upstream is established or reused.
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        reused: bool,
+        peer: &HttpPeer,
+        fd: RawFd,
+        _digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        // h2 connections are multiplexed and can not be closed per request
+        if !matches!(peer.options.alpn, ALPN::H1) {
+            return Ok(());
+        }
+
+        ctx.connection = ctx
+            .route
+            .as_ref()
+            .and_then(|route| route.resolve_upstream())
+            .and_then(|upstream| upstream.track_connection(fd, reused));
         Ok(())
     }
 
@@ -126,6 +182,28 @@ impl ProxyHttp for HttpService {
         upstream_response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) -> Result<()> {
+        let status = upstream_response.status.as_u16();
+
+        // feed the response status to the passive health check
+        if let Some(inflight) = ctx.inflight.as_ref() {
+            inflight.report_passive(PassiveEvent::Status(status));
+        }
+
+        // retry on another backend if the upstream asks for it
+        let retry_on_status = ctx
+            .route
+            .as_ref()
+            .and_then(|route| route.resolve_upstream())
+            .is_some_and(|upstream| upstream.retry_on_status(status));
+        if retry_on_status && can_retry_request(session) && try_retry(ctx) {
+            let mut e = Error::explain(
+                ErrorType::HTTPStatus(status),
+                "Retrying on upstream response status",
+            );
+            e.set_retry(true);
+            return Err(e);
+        }
+
         // execute global rule plugins
         global_plugin_fetch()
             .response_filter(session, upstream_response, ctx)
@@ -161,6 +239,53 @@ impl ProxyHttp for HttpService {
 
         // execute plugins
         ctx.plugin.clone().logging(session, e, ctx).await;
+
+        // The request to the upstream is finished
+        ctx.inflight = None;
+        ctx.connection = None;
+    }
+
+    /// This filter is called when there is an error after the connection to the upstream is established.
+    ///
+    /// Overriding it replaces the default implementation of pingora 0.4, whose
+    /// body is copied at the start and must be kept in sync when upgrading.
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        // Same as the default `ProxyHttp::error_while_proxy` of pingora 0.4
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        // only reused client connections where retry buffer is not truncated
+        e.retry
+            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+
+        let is_timeout = matches!(e.esource(), ErrorSource::Upstream)
+            && matches!(
+                e.etype(),
+                ErrorType::ReadTimedout | ErrorType::WriteTimedout
+            );
+        if !is_timeout {
+            return e;
+        }
+
+        // feed the timeout to the passive health check
+        if let Some(inflight) = ctx.inflight.as_ref() {
+            inflight.report_passive(PassiveEvent::Timeout);
+        }
+
+        let retry_on_timeout = ctx
+            .route
+            .as_ref()
+            .and_then(|route| route.resolve_upstream())
+            .is_some_and(|upstream| upstream.retry_on_timeout());
+        if retry_on_timeout && can_retry_request(session) && try_retry(ctx) {
+            e.set_retry(true);
+        }
+        e
     }
 
     /// This filter is called when there is an error in the process of establishing a connection to the upstream.
@@ -171,23 +296,407 @@ impl ProxyHttp for HttpService {
         ctx: &mut Self::CTX,
         mut e: Box<Error>,
     ) -> Box<Error> {
-        if let Some(route) = ctx.route.as_ref() {
-            if let Some(upstream) = route.resolve_upstream() {
-                if let Some(retries) = upstream.get_retries() {
-                    if retries == 0 || ctx.tries >= retries {
-                        return e;
-                    }
-                    if let Some(timeout) = upstream.get_retry_timeout() {
-                        if ctx.request_start.elapsed().as_millis() > (timeout * 1000) as _ {
-                            return e;
-                        }
-                    }
-                    ctx.tries += 1;
-                    e.set_retry(true);
-                    return e;
-                }
-            }
+        // feed the connection failure to the passive health check
+        if let Some(inflight) = ctx.inflight.as_ref() {
+            let event = match e.etype() {
+                ErrorType::ConnectTimedout => PassiveEvent::Timeout,
+                _ => PassiveEvent::TcpFailure,
+            };
+            inflight.report_passive(event);
+        }
+
+        if try_retry(ctx) {
+            e.set_retry(true);
         }
         e
     }
 }
+
+/// Counts another attempt if `retries` and `retry_timeout` of the upstream allow it.
+fn try_retry(ctx: &mut ProxyContext) -> bool {
+    let can_retry = ctx
+        .route
+        .as_ref()
+        .and_then(|route| route.resolve_upstream())
+        .is_some_and(|upstream| upstream.can_retry(ctx.tries, ctx.request_start.elapsed()));
+
+    if can_retry {
+        ctx.tries += 1;
+    }
+    can_retry
+}
+
+/// Only idempotent requests whose body can be replayed are retried after the
+/// upstream has received them.
+fn can_retry_request(session: &Session) -> bool {
+    matches!(
+        session.req_header().method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    ) && !session.as_ref().retry_buffer_truncated()
+}
+
+/// Starts a backend answering requests with `status` after `delay`,
+/// returns its port and the number of requests it received.
+#[cfg(test)]
+pub(crate) async fn test_backend(
+    status: u16,
+    delay: Duration,
+) -> (u16, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+    use std::sync::atomic::Ordering;
+
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    let received = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let received = received.clone();
+            tokio::spawn(async move {
+                read_request(&mut stream).await;
+                received.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(delay).await;
+                let response = format!(
+                    "HTTP/1.1 {} Test\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            });
+        }
+    });
+
+    (port, requests)
+}
+
+/// Reads a request head and its `Content-Length` body.
+#[cfg(test)]
+async fn read_request(stream: &mut tokio::net::TcpStream) {
+    use tokio::io::AsyncReadExt;
+
+    let mut buf = Vec::new();
+    let head_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            return;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_end]).to_lowercase();
+    let length: usize = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .map_or(0, |value| value.trim().parse().unwrap());
+    let mut body = buf.len() - head_end;
+    while body < length {
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            return;
+        }
+        body += n;
+    }
+}
+
+/// Registers a route and starts a proxy, returns the proxy address.
+#[cfg(test)]
+pub(crate) async fn test_proxy(route: &str) -> String {
+    use std::sync::Arc;
+
+    use pingora_core::{server::configuration::ServerConf, services::Service};
+    use pingora_proxy::http_proxy_service_with_name;
+    use tokio::net::TcpStream;
+
+    use crate::{
+        config,
+        proxy::{
+            route::{reload_global_match, ProxyRoute, ROUTE_MAP},
+            MapOperations,
+        },
+    };
+
+    let route: config::Route = serde_yaml::from_str(route).unwrap();
+    ROUTE_MAP.insert(Arc::new(
+        ProxyRoute::new_with_upstream_and_plugins(route, false).unwrap(),
+    ));
+    reload_global_match();
+
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let mut service =
+        http_proxy_service_with_name(&Arc::new(ServerConf::default()), HttpService, "test");
+    service.add_tcp(&addr);
+    let (shutdown, watch) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        let _shutdown = shutdown;
+        service.start_service(None, watch).await;
+    });
+
+    for _ in 0..100 {
+        if TcpStream::connect(&addr).await.is_ok() {
+            return addr;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("proxy did not start on {}", addr);
+}
+
+/// Sends a request through the proxy, returns the response status.
+#[cfg(test)]
+pub(crate) async fn test_request(
+    proxy: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> u16 {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\nConnection: close\r\n",
+        method,
+        path,
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(body).await.unwrap();
+
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+        .await
+        .expect("no response from the proxy")
+        .unwrap();
+    String::from_utf8_lossy(&response)
+        .split(' ')
+        .nth(1)
+        .unwrap()
+        .parse()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::Ordering, Arc};
+
+    use super::*;
+    use crate::{
+        config,
+        proxy::{plugin::test_session, route::ProxyRoute},
+    };
+
+    fn context(route: &str) -> ProxyContext {
+        let route: config::Route = serde_yaml::from_str(route).unwrap();
+        ProxyContext {
+            route: Some(Arc::new(
+                ProxyRoute::new_with_upstream_and_plugins(route, false).unwrap(),
+            )),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_try_retry() {
+        let mut ctx = context(
+            r#"
+id: "1"
+uri: /
+upstream:
+  retries: 2
+  nodes:
+    - host: 127.0.0.1
+      port: 1980
+      weight: 1
+"#,
+        );
+        assert!(try_retry(&mut ctx));
+        assert!(try_retry(&mut ctx));
+        assert_eq!(ctx.tries, 2);
+        // The retry budget is used up
+        assert!(!try_retry(&mut ctx));
+        assert_eq!(ctx.tries, 2);
+
+        let mut ctx = context(
+            r#"
+id: "1"
+uri: /
+upstream:
+  retries: 2
+  retry_timeout: 1
+  nodes:
+    - host: 127.0.0.1
+      port: 1980
+      weight: 1
+"#,
+        );
+        ctx.request_start -= Duration::from_secs(2);
+        assert!(!try_retry(&mut ctx));
+        assert_eq!(ctx.tries, 0);
+
+        // No route has been matched
+        assert!(!try_retry(&mut ProxyContext::default()));
+    }
+
+    #[tokio::test]
+    async fn test_can_retry_request() {
+        for method in ["GET", "HEAD", "PUT", "DELETE", "OPTIONS"] {
+            let (session, _client) = test_session(&format!("{method} / HTTP/1.1\r\n\r\n")).await;
+            assert!(can_retry_request(&session), "{method}");
+        }
+
+        // Non-idempotent methods are never retried
+        for method in ["POST", "PATCH"] {
+            let (session, _client) =
+                test_session(&format!("{method} / HTTP/1.1\r\nContent-Length: 0\r\n\r\n")).await;
+            assert!(!can_retry_request(&session), "{method}");
+        }
+    }
+
+    /// A route to a primary backend and a lower priority one, so that retries
+    /// only reach the second backend by excluding the tried one.
+    fn retry_route(id: &str, upstream: &str, primary: u16, secondary: u16) -> String {
+        format!(
+            r#"
+id: {id}
+uri: /{id}
+upstream:
+  {upstream}
+  nodes:
+    - {{host: 127.0.0.1, port: {primary}, weight: 1, priority: 1}}
+    - {{host: 127.0.0.1, port: {secondary}, weight: 1}}
+"#
+        )
+    }
+
+    #[tokio::test]
+    async fn test_retry_on_status() {
+        let (a, a_requests) = test_backend(502, Duration::ZERO).await;
+        let (b, b_requests) = test_backend(200, Duration::ZERO).await;
+        let proxy = test_proxy(&retry_route(
+            "retry-status",
+            "retries: 1\n  retry_on: {http_statuses: [502]}",
+            a,
+            b,
+        ))
+        .await;
+
+        // A 502 of the primary backend is retried on the other one
+        assert_eq!(
+            test_request(&proxy, "GET", "/retry-status", &[], b"").await,
+            200
+        );
+        assert_eq!(a_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(b_requests.load(Ordering::Relaxed), 1);
+
+        // Non-idempotent methods are not retried
+        assert_eq!(
+            test_request(&proxy, "POST", "/retry-status", &[], b"{}").await,
+            502
+        );
+        assert_eq!(a_requests.load(Ordering::Relaxed), 2);
+        assert_eq!(b_requests.load(Ordering::Relaxed), 1);
+
+        // Neither are bodies too large for the retry buffer
+        let body = vec![b'x'; 128 * 1024];
+        assert_eq!(
+            test_request(&proxy, "PUT", "/retry-status", &[], &body).await,
+            502
+        );
+        assert_eq!(a_requests.load(Ordering::Relaxed), 3);
+        assert_eq!(b_requests.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_limits() {
+        let (a, a_requests) = test_backend(502, Duration::ZERO).await;
+        let (b, b_requests) = test_backend(502, Duration::ZERO).await;
+        let proxy = test_proxy(&retry_route(
+            "retry-budget",
+            "retries: 1\n  retry_on: {http_statuses: [502]}",
+            a,
+            b,
+        ))
+        .await;
+
+        // The response of the last allowed attempt is returned
+        assert_eq!(
+            test_request(&proxy, "GET", "/retry-budget", &[], b"").await,
+            502
+        );
+        assert_eq!(a_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(b_requests.load(Ordering::Relaxed), 1);
+
+        let (a, a_requests) = test_backend(502, Duration::from_millis(1100)).await;
+        let (b, b_requests) = test_backend(200, Duration::ZERO).await;
+        let proxy = test_proxy(&retry_route(
+            "retry-timeout",
+            "retries: 1\n  retry_timeout: 1\n  retry_on: {http_statuses: [502]}",
+            a,
+            b,
+        ))
+        .await;
+
+        // No retry once the retry timeout has elapsed
+        assert_eq!(
+            test_request(&proxy, "GET", "/retry-timeout", &[], b"").await,
+            502
+        );
+        assert_eq!(a_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(b_requests.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_retry_on_read_timeout() {
+        let (a, a_requests) = test_backend(200, Duration::from_secs(60)).await;
+        let (b, b_requests) = test_backend(200, Duration::ZERO).await;
+        let proxy = test_proxy(&retry_route(
+            "retry-read-timeout",
+            "retries: 1\n  retry_on: {timeout: true}\n  timeout: {connect: 1, send: 1, read: 0.2}",
+            a,
+            b,
+        ))
+        .await;
+
+        assert_eq!(
+            test_request(&proxy, "GET", "/retry-read-timeout", &[], b"").await,
+            200
+        );
+        assert_eq!(a_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(b_requests.load(Ordering::Relaxed), 1);
+
+        // The timeout path is bounded by the retry budget too
+        let (a, a_requests) = test_backend(200, Duration::from_secs(60)).await;
+        let (b, b_requests) = test_backend(200, Duration::from_secs(60)).await;
+        let proxy = test_proxy(&retry_route(
+            "retry-read-budget",
+            "retries: 1\n  retry_on: {timeout: true}\n  timeout: {connect: 1, send: 1, read: 0.2}",
+            a,
+            b,
+        ))
+        .await;
+
+        assert_ne!(
+            test_request(&proxy, "GET", "/retry-read-budget", &[], b"").await,
+            200
+        );
+        assert_eq!(a_requests.load(Ordering::Relaxed), 1);
+        assert_eq!(b_requests.load(Ordering::Relaxed), 1);
+    }
+}